@@ -0,0 +1,207 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::Duration,
+};
+
+use symphonia::core::{formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions};
+
+/// suffix of the loudness normalized variant stored next to the original download
+pub const PROCESSED_SUFFIX: &str = "norm";
+/// drop leading silence, then normalize to the EBU R128 loudness streaming services use
+const PROCESSING_FILTER: &str = "silenceremove=start_periods=1:start_threshold=-50dB,\
+loudnorm=I=-16:TP=-1.5:LRA=11";
+
+/// sample rate the audio is decoded at for analysis, plenty for loudness
+const ANALYSIS_SAMPLE_RATE: usize = 8000;
+
+/// Loudness (RMS) of every second of the file, decoded through ffmpeg
+pub fn energy_profile(path: &Path) -> Option<Vec<f32>> {
+    let mut handle = process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-vn", "-ac", "1", "-ar"])
+        .arg(ANALYSIS_SAMPLE_RATE.to_string())
+        .args(["-f", "s16le", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| eprintln!("[AUDIO] couldn't spawn ffmpeg: {}", e))
+        .ok()?;
+
+    let mut pcm = Vec::new();
+    handle.stdout.take()?.read_to_end(&mut pcm).ok()?;
+    if !handle.wait().ok()?.success() {
+        return None;
+    }
+
+    let profile = pcm
+        .chunks(ANALYSIS_SAMPLE_RATE * 2)
+        .map(|second| {
+            let sum_squares = second
+                .chunks_exact(2)
+                .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / i16::MAX as f32)
+                .map(|s| s * s)
+                .sum::<f32>();
+            (sum_squares / (second.len() / 2).max(1) as f32).sqrt()
+        })
+        .collect::<Vec<_>>();
+    (!profile.is_empty()).then_some(profile)
+}
+
+/// Seconds of (near) silence at the start of an energy profile
+pub fn leading_silence(profile: &[f32]) -> usize {
+    let peak = profile.iter().cloned().fold(0.0, f32::max);
+    profile
+        .iter()
+        .position(|rms| *rms > peak * 0.1)
+        .unwrap_or_default()
+}
+
+/// Start (in seconds) of the loudest `window` seconds, a rough guess for the chorus
+pub fn loudest_window(profile: &[f32], window: usize, skip: usize) -> Option<usize> {
+    if profile.len() < window + skip || window == 0 {
+        return None;
+    }
+    profile
+        .windows(window)
+        .enumerate()
+        .skip(skip)
+        .map(|(start, frame)| (start, frame.iter().sum::<f32>()))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(start, _)| start)
+}
+
+/// A partial file to write `dest` to before renaming it, unique to every writer
+/// so two runs writing the same file never write into each other's output
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(format!(".{:08x}.part", rand::random::<u32>()));
+    PathBuf::from(part)
+}
+
+/// Cuts `length` of `src` starting at `offset` into `dest`, re-encoded to Opus in WebM
+/// since the source can be in any codec, like mp3 or aac from the local library.
+/// Written to a partial file first, so an interrupted run never leaves a truncated clip.
+pub fn cut_clip(src: &Path, dest: &Path, offset: Duration, length: Duration) -> bool {
+    let part = partial_path(dest);
+    let status = process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-ss"])
+        .arg(format!("{:.3}", offset.as_secs_f64()))
+        .arg("-t")
+        .arg(format!("{:.3}", length.as_secs_f64()))
+        .arg("-i")
+        .arg(src)
+        .args(["-vn", "-c:a", "libopus", "-b:a", "128k", "-f", "webm"])
+        .arg(&part)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => match std::fs::rename(&part, dest) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("[AUDIO] couldn't store clip {:?}: {}", dest, e);
+                false
+            }
+        },
+        Ok(status) => {
+            eprintln!("[AUDIO] ffmpeg cutting {:?} failed: {}", src, status);
+            let _ = std::fs::remove_file(&part);
+            false
+        }
+        Err(e) => {
+            eprintln!("[AUDIO] couldn't spawn ffmpeg: {}", e);
+            false
+        }
+    }
+}
+
+/// Whether symphonia recognizes the file as audio, without decoding it
+pub fn is_readable(path: &Path) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    symphonia::default::get_probe()
+        .format(
+            &Default::default(),
+            MediaSourceStream::new(Box::new(file), Default::default()),
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .is_ok()
+}
+
+pub fn processed_file_name(id: &str) -> String {
+    format!("{}.{}", id, PROCESSED_SUFFIX)
+}
+
+/// What `process_download` did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Processing {
+    /// the processed variant was just written
+    Fresh,
+    /// it was processed before
+    Existing,
+    Failed,
+}
+
+/// Writes a loudness normalized copy without leading silence next to the download.
+pub fn process_download(songs_dir: &Path, id: &str) -> Processing {
+    let processed = songs_dir.join(processed_file_name(id));
+    if processed.exists() {
+        return Processing::Existing;
+    }
+    // write to a temporary file first, so a half processed file is never served
+    let part = partial_path(&processed);
+    let status = process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(songs_dir.join(id))
+        .args(["-vn", "-af", PROCESSING_FILTER])
+        .args(["-c:a", "libopus", "-b:a", "128k", "-f", "webm"])
+        .arg(&part)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => match std::fs::rename(&part, &processed) {
+            Ok(_) => {
+                println!("[AUDIO] processed {}", id);
+                Processing::Fresh
+            }
+            Err(e) => {
+                eprintln!("[AUDIO] couldn't store processed {}: {}", id, e);
+                Processing::Failed
+            }
+        },
+        Ok(status) => {
+            eprintln!("[AUDIO] ffmpeg processing {} failed: {}", id, status);
+            let _ = std::fs::remove_file(&part);
+            Processing::Failed
+        }
+        Err(e) => {
+            eprintln!("[AUDIO] couldn't spawn ffmpeg: {}", e);
+            Processing::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_writer_gets_its_own_partial_file() {
+        let dest = Path::new("songs/clips/test:song-0-30");
+        let first = partial_path(dest);
+        let second = partial_path(dest);
+        assert_ne!(first, second);
+        for part in [first, second] {
+            let name = part.file_name().unwrap().to_string_lossy().to_string();
+            assert!(name.starts_with("test:song-0-30."));
+            assert!(name.ends_with(".part"));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    config::{config, ConfigOverrides},
+    music_handler::{
+        self,
+        download_queue::{self, DownloadRequester, DownloadStatus},
+        prefetch, song_cache,
+    },
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "The server behind Guess The Song")]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    /// `serve` when left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the game server
+    Serve,
+    /// Manage the downloaded songs
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Probe the Invidious instances and rank them by response time
+    Instances,
+    /// Search the music sources like players do
+    Search {
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List the downloaded songs
+    List,
+    /// Remove partial downloads and cut clips, they are cut again when needed
+    Prune {
+        /// also remove songs that were not played for this many days
+        #[arg(long, value_name = "DAYS")]
+        older_than: Option<u64>,
+    },
+    /// Check that every downloaded song can be read as audio
+    Verify {
+        /// delete the songs that can't be read, they are downloaded again when needed
+        #[arg(long)]
+        delete: bool,
+    },
+    /// Download songs, or every song of playlists or channels, ahead of time.
+    /// Without IDs the `[prefetch]` songs of the config and the most added songs are downloaded.
+    Prefetch { ids: Vec<String> },
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn days_ago(time: SystemTime) -> u64 {
+    SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs()
+        / (60 * 60 * 24)
+}
+
+/// Runs a command other than `serve`
+pub fn run(command: Command) -> ExitCode {
+    match command {
+        Command::Serve => unreachable!("serve is run by main"),
+        Command::Cache(command) => run_cache(command),
+        Command::Instances => {
+            println!("probing instances...");
+            let results = music_handler::probe_instances();
+            for (rank, (instance, result)) in results.iter().enumerate() {
+                match result {
+                    Ok(elapsed) => println!(
+                        "{:>3}. {:<40} {} ms",
+                        rank + 1,
+                        instance,
+                        elapsed.as_millis()
+                    ),
+                    Err(err) => println!("  -  {:<40} unreachable: {}", instance, err),
+                }
+            }
+            match results.iter().any(|(_, result)| result.is_ok()) {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        Command::Search { query } => {
+            music_handler::start_sources();
+            match music_handler::get_suggestions(&query.join(" ")) {
+                Ok(results) => {
+                    for result in results {
+                        println!("{:<9} {:<24} {}", result.r#type, result.id, result.name);
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    eprintln!("search failed: {:?}", err);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+fn run_cache(command: CacheCommand) -> ExitCode {
+    match command {
+        CacheCommand::List => {
+            let entries = song_cache::entries();
+            for entry in &entries {
+                println!(
+                    "{:<24} {:>10} {:>9} {:>4} days unused  {}",
+                    entry.id,
+                    human_size(entry.size),
+                    if entry.processed { "processed" } else { "" },
+                    days_ago(entry.modified),
+                    entry.song.as_ref().map_or(String::new(), |song| format!(
+                        "{} - {}",
+                        song.artist, song.title
+                    ))
+                );
+            }
+            println!(
+                "{} songs, {}",
+                entries.len(),
+                human_size(entries.iter().map(|entry| entry.size).sum())
+            );
+            ExitCode::SUCCESS
+        }
+        CacheCommand::Prune { older_than } => {
+            let max_age = older_than.map(|days| Duration::from_secs(days * 60 * 60 * 24));
+            match song_cache::prune(max_age) {
+                Ok(report) => {
+                    println!(
+                        "removed {} files, {}",
+                        report.files,
+                        human_size(report.bytes)
+                    );
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    eprintln!("pruning failed: {}", err);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        CacheCommand::Verify { delete } => {
+            let broken = song_cache::verify();
+            for id in &broken {
+                match delete {
+                    true => match song_cache::remove(id) {
+                        Ok(_) => println!("{}: unreadable, deleted", id),
+                        Err(err) => println!("{}: unreadable, couldn't delete: {}", id, err),
+                    },
+                    false => println!("{}: unreadable", id),
+                }
+            }
+            println!("{} unreadable songs", broken.len());
+            match broken.is_empty() || delete {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        CacheCommand::Prefetch { mut ids } => {
+            music_handler::start_sources();
+            download_queue::start_download_workers();
+            let requester = DownloadRequester {
+                user_id: 0,
+                listener: Some(Arc::new(|id: &str, status: &DownloadStatus| {
+                    println!("{}: {}", id, status.name())
+                })),
+            };
+            if ids.is_empty() {
+                ids = config().prefetch.ids.clone();
+                let popular = prefetch::most_added(config().prefetch.popular_count);
+                ids.extend(
+                    popular
+                        .into_iter()
+                        .filter(|id| !ids.contains(id))
+                        .collect::<Vec<_>>(),
+                );
+            }
+            let (mut ready, mut failed) = (0, 0);
+            for id in &ids {
+                match music_handler::prefetch(id, requester.clone()) {
+                    Ok(results) => {
+                        let errors = results.iter().filter(|result| result.is_err()).count();
+                        ready += results.len() - errors;
+                        failed += errors;
+                    }
+                    Err(err) => {
+                        eprintln!("couldn't prefetch {}: {:?}", id, err);
+                        failed += 1;
+                    }
+                }
+            }
+            println!("{} songs ready, {} failed", ready, failed);
+            match failed {
+                0 => ExitCode::SUCCESS,
+                _ => ExitCode::FAILURE,
+            }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Args;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer};
+
+use crate::{game::timings::GameTimings, music_handler};
+
+/// read when no `--config` is given, it's fine if it doesn't exist
+const DEFAULT_CONFIG_FILE: &str = "gts.toml";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The configuration the server was started with, the defaults if it wasn't loaded
+pub fn config() -> &'static Config {
+    // tests must not touch the real songs directory or sources
+    #[cfg(test)]
+    return CONFIG.get_or_init(crate::tests::test_config);
+    #[cfg(not(test))]
+    CONFIG.get_or_init(Config::default)
+}
+
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        eprintln!("[CONFIG] configuration was already loaded, keeping the first one");
+    }
+}
+
+/// Command line flags, they override the environment, which overrides the config file
+#[derive(Args, Debug, Default)]
+pub struct ConfigOverrides {
+    /// TOML configuration file, `gts.toml` is used if it exists
+    #[arg(long, env = "GTS_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// address to listen on
+    #[arg(long, env = "GTS_BIND", global = true)]
+    pub bind: Option<String>,
+    /// port to listen on
+    #[arg(long, env = "GTS_PORT", global = true)]
+    pub port: Option<u16>,
+    /// directory downloaded songs are stored in and served from
+    #[arg(long, env = "GTS_SONGS_DIR", global = true)]
+    pub songs_dir: Option<PathBuf>,
+    /// comma separated names of the music sources to use
+    #[arg(long, env = "GTS_MUSIC_SOURCES", value_delimiter = ',', global = true)]
+    pub sources: Option<Vec<String>>,
+    /// directory of the `local` music source
+    #[arg(long, env = "GTS_LOCAL_LIBRARY", global = true)]
+    pub local_library: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub sources: SourcesConfig,
+    pub search: SearchConfig,
+    pub cache: CacheConfig,
+    pub prefetch: PrefetchConfig,
+    pub invidious: InvidiousConfig,
+    pub yt_dlp: YtDlpConfig,
+    pub game: GameTimings,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    /// route the songs directory is served under
+    pub songs_route: String,
+    /// relative to the working directory
+    pub songs_dir: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            songs_route: "/songs".to_string(),
+            songs_dir: PathBuf::from("songs_cache"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    /// names of the music sources, searched in this order
+    pub enabled: Vec<String>,
+    /// directory of the `local` source
+    pub local_library: PathBuf,
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: vec!["invidious".to_string()],
+            local_library: PathBuf::from("music"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    /// how many queries keep their results, 0 to not cache searches
+    pub cache_size: usize,
+    /// how long search results are reused
+    #[serde(rename = "cache_ttl_ms", deserialize_with = "duration_ms")]
+    pub cache_ttl: Duration,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 1000,
+            cache_ttl: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// disk quota of the songs directory in MiB, 0 for no limit.
+    /// The least recently played songs are evicted first.
+    pub max_size_mb: u64,
+    /// how often the quota is enforced and leftovers of interrupted downloads removed
+    #[serde(rename = "sweep_interval_ms", deserialize_with = "duration_ms")]
+    pub sweep_interval: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 2048,
+            sweep_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrefetchConfig {
+    /// songs and collections downloaded in the background and never evicted
+    pub ids: Vec<String>,
+    /// how many of the most added songs are kept downloaded as well
+    pub popular_count: usize,
+    /// how often missing songs are downloaded again
+    #[serde(rename = "interval_ms", deserialize_with = "duration_ms")]
+    pub interval: Duration,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            ids: vec![],
+            popular_count: 25,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvidiousConfig {
+    /// list of instances sorted by health, empty to always use the backup instances
+    pub instances_api: String,
+    /// how many of the healthiest instances are used
+    pub instance_count: usize,
+    /// used when the instances API can't be reached
+    pub backup_instances: Vec<String>,
+}
+
+impl Default for InvidiousConfig {
+    fn default() -> Self {
+        Self {
+            instances_api: "https://api.invidious.io/instances.json?sort_by=health".to_string(),
+            instance_count: 3,
+            backup_instances: vec![
+                "yt.oelrichsgarcia.de".to_string(),
+                "invidious.einfachzocken.eu".to_string(),
+                "iv.nboeck.de".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct YtDlpConfig {
+    /// format selector passed to `-f`
+    pub format: String,
+    /// passed to `--max-filesize`, like `6000k` or `10M`
+    pub max_filesize: String,
+}
+
+impl Default for YtDlpConfig {
+    fn default() -> Self {
+        Self {
+            format: "bestaudio[acodec=opus]".to_string(),
+            max_filesize: "6000k".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "couldn't read {:?}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "invalid config file {:?}: {}", path, err),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+/// Durations are written as milliseconds in the config file
+pub fn duration_ms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+impl Config {
+    /// Loads the config file and applies the overrides of the environment and command line
+    pub fn load(overrides: &ConfigOverrides) -> Result<Config, ConfigError> {
+        let mut config = match &overrides.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.into(), e))
+    }
+
+    fn apply(&mut self, overrides: &ConfigOverrides) {
+        if let Some(bind) = &overrides.bind {
+            self.server.bind = bind.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if let Some(songs_dir) = &overrides.songs_dir {
+            self.server.songs_dir = songs_dir.clone();
+        }
+        if let Some(sources) = &overrides.sources {
+            self.sources.enabled = sources.iter().map(|name| name.trim().to_string()).collect();
+        }
+        if let Some(local_library) = &overrides.local_library {
+            self.sources.local_library = local_library.clone();
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+
+        let route = &self.server.songs_route;
+        if !route.starts_with('/') || route.len() < 2 || route.ends_with('/') {
+            return invalid(format!(
+                "songs_route must look like `/songs`, not `{}`",
+                route
+            ));
+        }
+        if self.sources.enabled.is_empty() {
+            return invalid("no music source is enabled".to_string());
+        }
+        if let Some(unknown) = self
+            .sources
+            .enabled
+            .iter()
+            .find(|name| !music_handler::is_known_source(name))
+        {
+            return invalid(format!("unknown music source `{}`", unknown));
+        }
+        if self.invidious.instance_count == 0 {
+            return invalid("instance_count must be at least 1".to_string());
+        }
+        if self.sources.enabled.iter().any(|name| name == "invidious")
+            && self.invidious.backup_instances.is_empty()
+        {
+            return invalid("the invidious source needs at least one backup instance".to_string());
+        }
+        let size = &self.yt_dlp.max_filesize;
+        let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G']);
+        if digits.is_empty() || digits.parse::<f64>().is_err() {
+            return invalid(format!(
+                "max_filesize must look like `6000k` or `10M`, not `{}`",
+                size
+            ));
+        }
+        if self.cache.sweep_interval.is_zero() {
+            return invalid("sweep_interval must not be zero".to_string());
+        }
+        if self.prefetch.interval.is_zero() {
+            return invalid("the prefetch interval must not be zero".to_string());
+        }
+        if self.game.guess_timeout.is_zero() || self.game.preload_timeout.is_zero() {
+            return invalid("guess and preload timeouts must not be zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_values_are_overridden_by_flags() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 9000
+            songs_dir = "/srv/songs"
+
+            [game]
+            guess_timeout_ms = 30000
+            "#,
+        )
+        .unwrap();
+        config.apply(&ConfigOverrides {
+            port: Some(9001),
+            sources: Some(vec!["mock".to_string(), " local".to_string()]),
+            ..ConfigOverrides::default()
+        });
+        config.validate().unwrap();
+
+        assert_eq!(config.server.port, 9001);
+        assert_eq!(config.server.songs_dir, PathBuf::from("/srv/songs"));
+        assert_eq!(config.server.bind, "127.0.0.1");
+        assert_eq!(config.sources.enabled, ["mock", "local"]);
+        assert_eq!(config.game.guess_timeout, Duration::from_secs(30));
+        assert_eq!(config.game.reveal, GameTimings::default().reveal);
+    }
+
+    #[test]
+    fn example_config_matches_the_defaults() {
+        let example: Config = toml::from_str(include_str!("../gts.example.toml")).unwrap();
+        assert_eq!(
+            format!(
+                "{:?}",
+                (
+                    example.server,
+                    example.sources,
+                    example.search,
+                    example.cache,
+                    example.prefetch,
+                    example.invidious,
+                    example.yt_dlp,
+                    example.game
+                )
+            ),
+            format!(
+                "{:?}",
+                (
+                    ServerConfig::default(),
+                    SourcesConfig::default(),
+                    SearchConfig::default(),
+                    CacheConfig::default(),
+                    PrefetchConfig::default(),
+                    InvidiousConfig::default(),
+                    YtDlpConfig::default(),
+                    GameTimings::default()
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert!(toml::from_str::<Config>("[server]\nprot = 1").is_err());
+
+        let check = |edit: fn(&mut Config)| {
+            let mut config = Config::default();
+            edit(&mut config);
+            config.validate()
+        };
+        assert!(check(|_| ()).is_ok());
+        assert_eq!(Config::default().sources.enabled, ["invidious"]);
+        assert!(check(|c| c.invidious.backup_instances.clear()).is_err());
+        assert!(check(|c| c.sources.enabled.clear()).is_err());
+        assert!(check(|c| c.server.songs_route = "songs".to_string()).is_err());
+        assert!(check(|c| c.sources.enabled = vec!["spotify".to_string()]).is_err());
+        assert!(check(|c| c.invidious.instance_count = 0).is_err());
+        assert!(check(|c| c.yt_dlp.max_filesize = "big".to_string()).is_err());
+        assert!(check(|c| c.yt_dlp.max_filesize = "10M".to_string()).is_ok());
+        assert!(check(|c| c.game.guess_timeout = Duration::ZERO).is_err());
+        assert!(check(|c| c.cache.sweep_interval = Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::music_handler;
+
+use super::{guessing_songs::MAX_START_LATENCY, timings::GameTimings};
+
+/// how much longer a token stays valid than the round can take, even if the reveal never happens
+const TOKEN_GRACE: Duration = Duration::from_secs(60);
+
+/// Tokens are issued while the leaderboard of the previous round is shown
+/// and are needed until the guessing ends
+fn token_ttl(timings: &GameTimings) -> Duration {
+    timings.leaderboard
+        + timings.preload_timeout
+        + timings.play_start_lead
+        + MAX_START_LATENCY
+        + timings.guess_timeout
+        + TOKEN_GRACE
+}
+
+struct AudioToken {
+    file: PathBuf,
+    expires: Instant,
+}
+
+static TOKENS: Lazy<RwLock<HashMap<String, AudioToken>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Hands out an opaque token for `file` (relative to the songs directory),
+/// so clients can't tell which song is playing from the URL.
+/// It lasts as long as a round of a game with `timings` can take.
+pub fn issue(file: &str, timings: &GameTimings) -> String {
+    let token = format!("{:032x}", rand::random::<u128>());
+    let mut tokens = TOKENS.write().unwrap();
+    let now = Instant::now();
+    tokens.retain(|_, audio_token| audio_token.expires > now);
+    tokens.insert(
+        token.clone(),
+        AudioToken {
+            file: music_handler::songs_dir().join(file),
+            expires: now + token_ttl(timings),
+        },
+    );
+    token
+}
+
+/// The file a token grants access to, if it is still valid
+pub fn resolve(token: &str) -> Option<PathBuf> {
+    TOKENS
+        .read()
+        .unwrap()
+        .get(token)
+        .filter(|audio_token| audio_token.expires > Instant::now())
+        .map(|audio_token| audio_token.file.clone())
+}
+
+pub fn revoke(token: &str) {
+    TOKENS.write().unwrap().remove(token);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_outlive_long_rounds() {
+        let timings = GameTimings {
+            guess_timeout: Duration::from_secs(60 * 60),
+            ..GameTimings::default()
+        };
+        let token = issue("test:long", &timings);
+        let expires = TOKENS.read().unwrap()[&token].expires;
+        revoke(&token);
+        assert!(expires > Instant::now() + timings.leaderboard + timings.guess_timeout);
+    }
+}
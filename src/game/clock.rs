@@ -0,0 +1,195 @@
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::model::clock_sync::server_time_ms;
+
+/// Time as the game engine sees it. Games run on the real clock,
+/// tests swap in a `VirtualClock` to step through the timing rules.
+pub trait Clock: Send + Sync {
+    /// time since the clock started, only differences are meaningful
+    fn now(&self) -> Duration;
+
+    /// milliseconds since the UNIX epoch, the time clients are told
+    fn unix_ms(&self) -> u128;
+
+    /// blocks until `now()` reached `deadline`
+    fn sleep_until(&self, deadline: Duration);
+
+    /// how long to block on a channel at once while waiting for `deadline`
+    fn poll_interval(&self, deadline: Duration) -> Duration;
+
+    fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration);
+    }
+}
+
+/// Receives from `rx` until `deadline` of `clock` passed
+pub fn recv_until<T>(
+    clock: &dyn Clock,
+    rx: &Receiver<T>,
+    deadline: Duration,
+) -> Result<T, RecvTimeoutError> {
+    loop {
+        if clock.now() >= deadline {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        match rx.recv_timeout(clock.poll_interval(deadline)) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            received => return received,
+        }
+    }
+}
+
+pub struct RealClock {
+    started: Instant,
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn unix_ms(&self) -> u128 {
+        server_time_ms()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        thread::sleep(deadline.saturating_sub(self.now()));
+    }
+
+    fn poll_interval(&self, deadline: Duration) -> Duration {
+        deadline.saturating_sub(self.now())
+    }
+}
+
+static REAL_CLOCK: Lazy<Arc<RealClock>> = Lazy::new(|| {
+    Arc::new(RealClock {
+        started: Instant::now(),
+    })
+});
+
+pub fn real() -> Arc<dyn Clock> {
+    REAL_CLOCK.clone()
+}
+
+/// A clock that only moves when `advance` is called
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct VirtualClock {
+    unix_start_ms: u128,
+    state: Mutex<VirtualState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct VirtualState {
+    now: Duration,
+    /// deadlines of the threads sleeping on the clock
+    sleeping: Vec<Duration>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl VirtualClock {
+    /// how often waiting for a channel checks whether the clock moved
+    const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+    pub fn new() -> Self {
+        Self {
+            unix_start_ms: server_time_ms(),
+            state: Mutex::new(VirtualState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Moves the clock forward and waits until the sleepers it woke up are running again
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += by;
+        self.changed.notify_all();
+        while state.sleeping.iter().any(|deadline| *deadline <= state.now) {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks until `count` threads are sleeping on the clock,
+    /// so advancing it afterwards wakes them deterministically
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.sleeping.len() < count {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    fn unix_ms(&self) -> u128 {
+        self.unix_start_ms + self.now().as_millis()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.sleeping.push(deadline);
+        self.changed.notify_all();
+        while state.now < deadline {
+            state = self.changed.wait(state).unwrap();
+        }
+        if let Some(idx) = state.sleeping.iter().position(|d| *d == deadline) {
+            state.sleeping.swap_remove(idx);
+        }
+        self.changed.notify_all();
+    }
+
+    fn poll_interval(&self, _deadline: Duration) -> Duration {
+        Self::POLL_INTERVAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn virtual_sleep_waits_for_advance() {
+        let clock = Arc::new(VirtualClock::new());
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep(Duration::from_secs(60)))
+        };
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(59));
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(1));
+        sleeper.join().unwrap();
+        assert_eq!(clock.now(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn virtual_recv_times_out_only_after_advance() {
+        let clock = VirtualClock::new();
+        let (tx, rx) = channel::<()>();
+        let rx = Mutex::new(rx);
+        let deadline = clock.now() + Duration::from_secs(180);
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| recv_until(&clock, &rx.lock().unwrap(), deadline).is_err());
+            thread::sleep(Duration::from_millis(20));
+            assert!(!receiver.is_finished());
+            clock.advance(Duration::from_secs(180));
+            assert!(receiver.join().unwrap());
+        });
+        drop(tx);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+use crate::{
+    model::song::{year_from_unix, Song},
+    music_handler,
+};
+
+use super::questions::{decade_name, GuessOption, QuestionType};
+
+pub const OPTION_COUNT: usize = 4;
+
+/// collects distinct options, ignoring duplicates by their normalized key
+struct OptionSet {
+    seen: HashSet<String>,
+    options: Vec<GuessOption>,
+    count: usize,
+}
+
+impl OptionSet {
+    /// A set of `count` distractors for `correct`, which is never added to it
+    fn new(count: usize, correct: &GuessOption) -> Self {
+        Self {
+            seen: HashSet::from([correct.key()]),
+            options: Vec::with_capacity(count),
+            count,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.options.len() >= self.count
+    }
+
+    fn push(&mut self, option: GuessOption) {
+        if self.is_full() || option.is_empty() {
+            return;
+        }
+        if self.seen.insert(option.key()) {
+            self.options.push(option);
+        }
+    }
+
+    fn extend(&mut self, candidates: impl IntoIterator<Item = GuessOption>) {
+        for option in candidates {
+            if self.is_full() {
+                return;
+            }
+            self.push(option);
+        }
+    }
+}
+
+fn shuffled(mut songs: Vec<Song>, question: QuestionType) -> impl Iterator<Item = GuessOption> {
+    songs.shuffle(&mut thread_rng());
+    songs.into_iter().map(move |song| question.answer(&song))
+}
+
+/// text answers are topped up from the game songs and cached metadata
+fn fill_from_songs(
+    set: &mut OptionSet,
+    question: QuestionType,
+    correct: &GuessOption,
+    song: &Song,
+    game_songs: &[Song],
+) {
+    set.extend(shuffled(
+        game_songs.iter().filter(|s| s != &song).cloned().collect(),
+        question,
+    ));
+    if !set.is_full() {
+        set.extend(shuffled(music_handler::related_songs(song), question));
+    }
+    if !set.is_full() {
+        set.extend(shuffled(music_handler::known_songs(), question));
+    }
+    if !set.is_full() && question == QuestionType::TitleArtist {
+        // last resort: mix titles and artists of the options we already have
+        let pairs = set
+            .options
+            .iter()
+            .chain([correct])
+            .filter_map(|option| match option {
+                GuessOption::TitleArtist(title, artist) => Some((title.clone(), artist.clone())),
+                GuessOption::Single(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let mut mixed = pairs
+            .iter()
+            .flat_map(|(title, _)| {
+                pairs
+                    .iter()
+                    .map(|(_, artist)| GuessOption::TitleArtist(title.clone(), artist.clone()))
+            })
+            .collect::<Vec<_>>();
+        mixed.shuffle(&mut thread_rng());
+        set.extend(mixed);
+    }
+}
+
+fn current_year() -> i32 {
+    year_from_unix(
+        std::time::UNIX_EPOCH
+            .elapsed()
+            .expect("system to provide elapsed UNIX time")
+            .as_secs(),
+    )
+}
+
+/// years and decades are generated around the correct one, so there are always enough
+fn fill_from_range(set: &mut OptionSet, question: QuestionType, year: i32) {
+    let step = match question {
+        QuestionType::Decade => 10,
+        _ => 1,
+    };
+    let format = |year: i32| match question {
+        QuestionType::Decade => GuessOption::Single(decade_name(year)),
+        _ => GuessOption::Single(year.to_string()),
+    };
+    // shift the window so the correct answer is not always in the middle,
+    // but don't offer years that haven't happened yet
+    let count = set.count as i32 + 1;
+    let past_now = (year + (count - 1) * step - current_year()) / step;
+    let below = thread_rng().gen_range(past_now.clamp(0, count - 1)..count);
+    let first = year - below * step;
+    set.extend((0..count * 2).map(|i| format(first + i * step)));
+}
+
+/// Obviously wrong options for when there aren't enough songs to take distractors from
+fn fill_with_placeholders(set: &mut OptionSet, question: QuestionType) {
+    let mut n = 1;
+    while !set.is_full() {
+        set.push(match question {
+            QuestionType::TitleArtist => {
+                GuessOption::TitleArtist(format!("Untitled {}", n), "Unknown Artist".to_string())
+            }
+            QuestionType::Artist => GuessOption::Single(format!("Unknown Artist {}", n)),
+            _ => GuessOption::Single(format!("Untitled {}", n)),
+        });
+        n += 1;
+    }
+}
+
+/// Builds `count` distinct answer options for `song`, the correct one included.
+///
+/// Distractors are taken from the other songs of the game first, then topped up with
+/// cached metadata of related songs (same artist, same search results), any known song
+/// and finally placeholders. Returns the shuffled options and the index of the correct one.
+pub fn answer_options(
+    question: QuestionType,
+    song: &Song,
+    game_songs: &[Song],
+    count: usize,
+) -> (Vec<GuessOption>, u8) {
+    let correct = question.answer(song);
+    let mut set = OptionSet::new(count.saturating_sub(1), &correct);
+
+    match (question, song.year()) {
+        (QuestionType::Year | QuestionType::Decade, Some(year)) => {
+            fill_from_range(&mut set, question, year)
+        }
+        _ => fill_from_songs(&mut set, question, &correct, song, game_songs),
+    }
+    fill_with_placeholders(&mut set, question);
+
+    // the correct option goes in even if it is blank, it can't collide with a distractor
+    let mut options = set.options;
+    options.insert(0, correct);
+    let mut order = (0..options.len()).collect::<Vec<_>>();
+    order.shuffle(&mut thread_rng());
+    let correct_idx = order.iter().position(|&idx| idx == 0).unwrap_or_default() as u8;
+    let options = order.into_iter().map(|idx| options[idx].clone()).collect();
+    (options, correct_idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, title: &str, artist: &str) -> Song {
+        Song {
+            id: id.to_string(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+            length: 0,
+            published: 0,
+            album: None,
+            release_year: None,
+        }
+    }
+
+    fn assert_valid(options: &[GuessOption], correct_idx: u8, correct: &GuessOption) {
+        assert_eq!(options.len(), OPTION_COUNT);
+        assert_eq!(&options[correct_idx as usize], correct);
+        let keys = options.iter().map(GuessOption::key).collect::<HashSet<_>>();
+        assert_eq!(keys.len(), OPTION_COUNT);
+    }
+
+    #[test]
+    fn small_pools_are_padded() {
+        let only = song("test:only", "Lonely Tune", "Solo Act");
+        for question in [
+            QuestionType::TitleArtist,
+            QuestionType::Title,
+            QuestionType::Artist,
+        ] {
+            let (options, correct_idx) =
+                answer_options(question, &only, std::slice::from_ref(&only), OPTION_COUNT);
+            assert_valid(&options, correct_idx, &question.answer(&only));
+        }
+    }
+
+    #[test]
+    fn blank_answers_stay_correct() {
+        let untitled = song("test:untitled", " ", "Solo Act");
+        let (options, correct_idx) =
+            answer_options(QuestionType::Title, &untitled, &[], OPTION_COUNT);
+        assert_valid(&options, correct_idx, &GuessOption::Single(" ".to_string()));
+    }
+
+    #[test]
+    fn duplicate_titles_are_offered_once() {
+        let game_songs = [
+            song("test:a", "Echo", "First"),
+            song("test:b", "echo ", "Second"),
+            song("test:c", "ECHO", "Third"),
+            song("test:d", "Other", "Fourth"),
+        ];
+        let (options, correct_idx) = answer_options(
+            QuestionType::Title,
+            &game_songs[0],
+            &game_songs,
+            OPTION_COUNT,
+        );
+        assert_valid(
+            &options,
+            correct_idx,
+            &GuessOption::Single("Echo".to_string()),
+        );
+        let echos = options.iter().filter(|option| option.key() == "echo");
+        assert_eq!(echos.count(), 1);
+        assert!(options.contains(&GuessOption::Single("Other".to_string())));
+    }
+
+    #[test]
+    fn distractors_come_from_the_game_songs_without_release_years() {
+        let game_songs = [
+            song("test:alpha", "Alpha", "Ann"),
+            song("test:beta", "Beta", "Ben"),
+            song("test:gamma", "Gamma", "Cat"),
+            song("test:delta", "Delta", "Dan"),
+        ];
+        for question in [QuestionType::Title, QuestionType::Artist] {
+            let (options, correct_idx) =
+                answer_options(question, &game_songs[0], &game_songs, OPTION_COUNT);
+            assert_valid(&options, correct_idx, &question.answer(&game_songs[0]));
+            let expected = game_songs
+                .iter()
+                .map(|song| question.answer(song).key())
+                .collect::<HashSet<_>>();
+            let offered = options.iter().map(GuessOption::key).collect::<HashSet<_>>();
+            assert_eq!(offered, expected);
+        }
+    }
+
+    #[test]
+    fn year_options_surround_the_release_year() {
+        let mut released = song("test:year", "Old Hit", "Band");
+        released.release_year = Some(1999);
+        for question in [QuestionType::Year, QuestionType::Decade] {
+            let (options, correct_idx) = answer_options(question, &released, &[], OPTION_COUNT);
+            assert_valid(&options, correct_idx, &question.answer(&released));
+        }
+    }
+}
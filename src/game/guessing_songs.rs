@@ -1,42 +1,77 @@
 use std::{
     collections::HashMap,
     sync::{
-        mpsc::{sync_channel, Receiver, SyncSender},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
         Arc, RwLock,
     },
     thread::{self, JoinHandle},
     time::Duration,
 };
 
-use rand::{
-    seq::{IteratorRandom, SliceRandom},
-    thread_rng,
+use rand::{seq::SliceRandom, thread_rng};
+
+use crate::{
+    model::{song::Song, user::User},
+    music_handler::song_cache,
+};
+
+use super::{
+    audio_tokens,
+    clock::{self, Clock},
+    distractors::{self, OPTION_COUNT},
+    questions::QuestionMode,
+    snippets::{self, Snippet, SnippetMode},
+    timings::GameTimings,
+    GameStatus, ServerMessage, GAMES,
 };
 
-use crate::model::{song::Song, user::User};
+/// what players send to the game thread while guessing
+#[derive(Debug)]
+pub enum RoundInput {
+    Guess(u8),
+    /// the client finished loading the audio of the given round
+    Loaded(u16),
+    /// the player left the game
+    Left,
+}
+
+pub type PlayerInput = (Arc<RwLock<User>>, RoundInput);
 
-use super::{GameStatus, ServerMessage, GAMES};
+/// the most client latency added to `play_start_lead`
+pub(super) const MAX_START_LATENCY: Duration = Duration::from_secs(2);
 
-pub type PlayerGuess = (Arc<RwLock<User>>, u8);
 pub fn handle_guessing(
     players: Vec<Arc<RwLock<User>>>,
     player_songs: &mut HashMap<usize, Vec<Song>>,
-) -> (SyncSender<PlayerGuess>, JoinHandle<()>) {
-    let (tx, rx) = sync_channel::<PlayerGuess>(2);
+    question_mode: QuestionMode,
+    snippet_mode: SnippetMode,
+    timings: GameTimings,
+    clock: Arc<dyn Clock>,
+) -> (SyncSender<PlayerInput>, JoinHandle<()>) {
+    // room for a guess, a preload confirmation and a leave of every player,
+    // they may arrive while the game thread is sleeping between rounds
+    let (tx, rx) = sync_channel::<PlayerInput>(players.len() * 3);
 
-    let songs = player_songs
-        .values_mut()
-        .map(|v| std::mem::take(v))
-        .flatten()
-        .collect();
+    let songs = player_songs.values_mut().flat_map(std::mem::take).collect();
 
-    let handle = thread::spawn(move || handle_game(players, songs, rx));
+    let handle = thread::spawn(move || {
+        handle_game(
+            players,
+            songs,
+            question_mode,
+            snippet_mode,
+            timings,
+            clock.as_ref(),
+            rx,
+        )
+    });
     (tx, handle)
 }
 
 pub fn handle_game_end(handle: JoinHandle<()>, game_id: u16) {
     thread::spawn(move || -> Option<()> {
         handle.join().unwrap();
+        song_cache::unpin_game(game_id);
         let mut games = GAMES.write().unwrap();
         let game = games.get_mut(&game_id)?;
         game.set_state(GameStatus::Lobby(0));
@@ -45,7 +80,7 @@ pub fn handle_game_end(handle: JoinHandle<()>, game_id: u16) {
     });
 }
 
-fn broadcast_users(users: &Vec<Arc<RwLock<User>>>, msg: ServerMessage) {
+pub fn broadcast_users(users: &Vec<Arc<RwLock<User>>>, msg: ServerMessage) {
     for user in users {
         if let Some(ws) = user.read().unwrap().ws.as_ref() {
             ws.do_send(msg.clone());
@@ -53,56 +88,146 @@ fn broadcast_users(users: &Vec<Arc<RwLock<User>>>, msg: ServerMessage) {
     }
 }
 
-fn song_to_title_artist_tuple(song: &Song) -> (String, String) {
-    (song.title.clone(), song.artist.clone())
+/// Picks the part of a song to play on its own thread, cutting a clip runs ffmpeg
+fn prepare_snippet(song: &Song, snippet_mode: SnippetMode) -> JoinHandle<Snippet> {
+    let song = song.clone();
+    thread::spawn(move || snippets::choose_snippet(snippet_mode, &song))
+}
+
+/// Waits for the snippet of a round and tells clients to start loading it
+fn announce_round(
+    players: &Vec<Arc<RwLock<User>>>,
+    round: usize,
+    snippet: JoinHandle<Snippet>,
+    timings: &GameTimings,
+) -> Snippet {
+    let mut snippet = snippet.join().expect("choosing a snippet not to panic");
+    snippet.file = audio_tokens::issue(&snippet.file, timings);
+    broadcast_users(
+        players,
+        ServerMessage::GamePreload(round as u16, snippet.file.clone()),
+    );
+    snippet
+}
+
+fn remove_player(players: &mut Vec<Arc<RwLock<User>>>, user: &Arc<RwLock<User>>) {
+    players.retain(|player| !Arc::ptr_eq(player, user));
+}
+
+/// Waits until every player loaded the audio of `round` or the preload timeout passed.
+/// Players that didn't confirm in time are reported to the lobby.
+/// `false` when the game was closed while waiting.
+fn await_preload(
+    players: &mut Vec<Arc<RwLock<User>>>,
+    user_msgs: &Receiver<PlayerInput>,
+    round: usize,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> bool {
+    let deadline = clock.now() + timeout;
+    let mut loaded: Vec<Arc<RwLock<User>>> = Vec::with_capacity(players.len());
+    while loaded.len() < players.len() {
+        match clock::recv_until(clock, user_msgs, deadline) {
+            Ok((user, RoundInput::Loaded(loaded_round))) if loaded_round as usize == round => {
+                if !loaded.iter().any(|u| Arc::ptr_eq(u, &user)) {
+                    loaded.push(user);
+                }
+            }
+            Ok((user, RoundInput::Left)) => {
+                remove_player(players, &user);
+                loaded.retain(|u| !Arc::ptr_eq(u, &user));
+            }
+            // stale guesses or confirmations of other rounds
+            Ok(_) => (),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+
+    let stragglers = players
+        .iter()
+        .filter(|player| !loaded.iter().any(|u| Arc::ptr_eq(u, player)))
+        .map(|player| player.read().unwrap().name.clone())
+        .collect::<Vec<_>>();
+    if !stragglers.is_empty() {
+        println!("[GAME] round {} started without {:?}", round, stragglers);
+        broadcast_users(players, ServerMessage::PreloadStragglers(stragglers));
+    }
+    true
 }
 
 fn handle_game(
-    players: Vec<Arc<RwLock<User>>>,
+    mut players: Vec<Arc<RwLock<User>>>,
     mut songs: Vec<Song>,
-    user_msgs: Receiver<PlayerGuess>,
+    question_mode: QuestionMode,
+    snippet_mode: SnippetMode,
+    timings: GameTimings,
+    clock: &dyn Clock,
+    user_msgs: Receiver<PlayerInput>,
 ) {
     let mut leaderboad: Vec<(Arc<RwLock<User>>, usize)> = Vec::new();
     songs.shuffle(&mut thread_rng());
 
-    let mut remaining_songs = songs.iter();
-    for song in &songs {
-        broadcast_users(&players, ServerMessage::GamePlayAudio(song.id.clone()));
-
-        let mut options = remaining_songs
-            .clone()
-            .map(song_to_title_artist_tuple)
-            .choose_multiple(&mut rand::thread_rng(), 4);
-        if !options
-            .iter()
-            .any(|(t, a)| t == &song.title && a == &song.artist)
-        {
-            *options.choose_mut(&mut thread_rng()).unwrap() = song_to_title_artist_tuple(song);
+    let mut next_snippet = songs
+        .first()
+        .map(|song| announce_round(&players, 0, prepare_snippet(song, snippet_mode), &timings));
+    for (round, song) in songs.iter().enumerate() {
+        let Some(snippet) = next_snippet.take() else {
+            break;
+        };
+        let open = await_preload(
+            &mut players,
+            &user_msgs,
+            round,
+            timings.preload_timeout,
+            clock,
+        );
+        if !open || players.is_empty() {
+            audio_tokens::revoke(&snippet.file);
+            break;
         }
-        if options.len() < 4 {
-            options.extend(
-                songs
+        let audio_token = snippet.file.clone();
+        // give the slowest client enough time to receive the message before playback starts,
+        // but don't let a single slow client hold up the game
+        let start_lead = timings.play_start_lead
+            + Duration::from_millis(
+                players
                     .iter()
-                    .filter(|s| s.id != song.id)
-                    .choose_multiple(&mut thread_rng(), 4 - options.len())
-                    .into_iter()
-                    .map(song_to_title_artist_tuple),
-            );
-        }
-        options.shuffle(&mut thread_rng());
-        let correct_idx = options
-            .iter()
-            .position(|(t, a)| t == &song.title && a == &song.artist)
-            .unwrap() as u8;
-        broadcast_users(&players, ServerMessage::GameGuessOptions(options.clone()));
-
-        let guessing_start: std::time::Instant = std::time::Instant::now();
-        let guess_timeout = Duration::from_secs(180);
-        let remaining = guess_timeout - guessing_start.elapsed();
+                    .map(|player| player.read().unwrap().clock.latency_ms())
+                    .max()
+                    .unwrap_or(0),
+            )
+            .min(MAX_START_LATENCY);
+        let start_at = clock.unix_ms() + start_lead.as_millis();
+        let guessing_start = clock.now() + start_lead;
+        broadcast_users(&players, ServerMessage::GamePlayAudio(snippet, start_at));
+        // cut while this round is played, not between rounds
+        let upcoming_snippet = songs
+            .get(round + 1)
+            .map(|song| prepare_snippet(song, snippet_mode));
+        song_cache::touch(&song.id);
+
+        let question = question_mode.question_for(song);
+        let (options, correct_idx) =
+            distractors::answer_options(question, song, &songs, OPTION_COUNT);
+        broadcast_users(&players, ServerMessage::GameQuestion(question));
+        broadcast_users(&players, ServerMessage::GameGuessOptions(options));
+
+        // guesses are timed from the scheduled start, not from when the message was sent
+        let guess_deadline = guessing_start + timings.guess_timeout;
         let mut guessed_count = 0;
-        while guessed_count < players.len() && guessing_start.elapsed() < Duration::from_secs(180) {
-            if let Ok((user, guess)) = user_msgs.recv_timeout(remaining) {
-                let guessed_at = guessing_start.elapsed().as_secs() * 10;
+        while guessed_count < players.len() && clock.now() < guess_deadline {
+            let input = clock::recv_until(clock, &user_msgs, guess_deadline);
+            if let Err(RecvTimeoutError::Disconnected) = input {
+                // the game was closed, waiting for the deadline would only spin
+                audio_tokens::revoke(&audio_token);
+                return;
+            }
+            if let Ok((user, RoundInput::Left)) = &input {
+                remove_player(&mut players, user);
+            }
+            if let Ok((user, RoundInput::Guess(guess))) = input {
+                let guessed_at = clock.now().saturating_sub(guessing_start).as_secs() * 10;
                 if guess == correct_idx {
                     let user_score =
                         &mut match leaderboad.iter_mut().find(|(u, _)| Arc::ptr_eq(&user, u)) {
@@ -122,9 +247,13 @@ fn handle_game(
                 guessed_count += 1;
             }
         }
-        remaining_songs.next();
         broadcast_users(&players, ServerMessage::Correct(correct_idx));
-        thread::sleep(Duration::from_secs(2));
+        audio_tokens::revoke(&audio_token);
+        clock.sleep(timings.reveal);
+
+        // let clients load the next song while the leaderboard is shown
+        next_snippet =
+            upcoming_snippet.map(|snippet| announce_round(&players, round + 1, snippet, &timings));
 
         // rx.try_recv()
         broadcast_users(
@@ -137,7 +266,70 @@ fn handle_game(
                     .collect(),
             ),
         );
-        thread::sleep(Duration::from_secs(5));
+        clock.sleep(timings.leaderboard);
+    }
+    clock.sleep(timings.game_end);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::model::clock_sync::ClockSync;
+
+    use super::*;
+
+    fn player() -> Arc<RwLock<User>> {
+        Arc::new(RwLock::new(User {
+            id: rand::random(),
+            name: "Closer".to_string(),
+            game_id: None,
+            ws: None,
+            clock: ClockSync::default(),
+        }))
+    }
+
+    /// Starts a one song game that would wait an hour for guesses and closes it,
+    /// optionally after the round started
+    fn closed_game_ends(start_round: bool) -> bool {
+        let player = player();
+        let song = Song {
+            id: "test:closed".to_string(),
+            title: "Closed".to_string(),
+            artist: "Nobody".to_string(),
+            length: 30,
+            published: 0,
+            album: None,
+            release_year: None,
+        };
+        let timings = GameTimings {
+            guess_timeout: Duration::from_secs(60 * 60),
+            preload_timeout: Duration::from_secs(60 * 60),
+            game_end: Duration::ZERO,
+            ..GameTimings::default()
+        };
+        let (tx, handle) = handle_guessing(
+            vec![player.clone()],
+            &mut HashMap::from([(0, vec![song])]),
+            QuestionMode::default(),
+            SnippetMode::Full,
+            timings,
+            clock::real(),
+        );
+        if start_round {
+            tx.send((player, RoundInput::Loaded(0))).unwrap();
+        }
+        drop(tx);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.is_finished()
+    }
+
+    #[test]
+    fn closed_games_stop_waiting_for_players() {
+        assert!(closed_game_ends(false));
+        assert!(closed_game_ends(true));
     }
-    thread::sleep(Duration::from_secs(10));
 }
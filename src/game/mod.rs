@@ -1,21 +1,38 @@
+pub mod audio_tokens;
+pub mod clock;
+mod distractors;
 mod guessing_songs;
+pub mod questions;
+pub mod snippets;
+pub mod timings;
 
 use std::{
     collections::HashMap,
     sync::{mpsc::SyncSender, Arc, RwLock, RwLockReadGuard},
     thread,
-    time::Duration,
 };
 
 use actix::{Addr, Message};
 use once_cell::sync::Lazy;
+use serde::Serialize;
 
 use crate::{
-    model::{song::Song, user::User},
-    music_handler, UserSocket,
+    model::{clock_sync::server_time_ms, search_result::SearchResult, song::Song, user::User},
+    music_handler::{
+        self,
+        download_queue::{DownloadRequester, DownloadStatus},
+        prefetch, song_cache,
+    },
+    UserSocket,
 };
 
-use self::guessing_songs::{handle_game_end, handle_guessing};
+use self::{
+    clock::Clock,
+    guessing_songs::{broadcast_users, handle_game_end, handle_guessing, PlayerInput, RoundInput},
+    questions::{GuessOption, QuestionMode, QuestionType},
+    snippets::{Snippet, SnippetMode},
+    timings::GameTimings,
+};
 
 static GAMES: Lazy<RwLock<HashMap<u16, Game>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
@@ -23,16 +40,20 @@ static GAMES: Lazy<RwLock<HashMap<u16, Game>>> = Lazy::new(|| RwLock::new(HashMa
 #[rtype(result = "()")]
 pub enum UserAction {
     SetUsername(String),
+    ClockPong(u128, u128),
     NewGame,
     JoinGame(u16),
     ReadyUp,
     Unready,
     StartGame,
+    SetQuestionMode(String),
+    SetSnippetMode(String),
     GetSuggestions(String),
     AddSong(String),
     RemoveSong(u32),
-    StartGuessing,
+    StartGuessing(bool),
     GuessSong(u8),
+    Loaded(u16),
     LeaveGame,
     InvalidAction,
 }
@@ -41,16 +62,29 @@ impl From<(&str, &str)> for UserAction {
     fn from(value: (&str, &str)) -> Self {
         match value {
             ("set_username", name) => UserAction::SetUsername(name.to_string()),
+            ("clock_pong", times) => match times.split_once(' ') {
+                Some((sent, client)) => match (sent.parse(), client.trim().parse()) {
+                    (Ok(sent), Ok(client)) => UserAction::ClockPong(sent, client),
+                    _ => UserAction::InvalidAction,
+                },
+                None => UserAction::InvalidAction,
+            },
             ("new", _) => UserAction::NewGame,
             ("join", game_id) => UserAction::JoinGame(game_id.parse().unwrap_or(0)),
             ("ready_up", _) => UserAction::ReadyUp,
             ("unready", _) => UserAction::Unready,
             ("start", _) => UserAction::StartGame,
+            ("question_mode", mode) => UserAction::SetQuestionMode(mode.to_string()),
+            ("snippet_mode", mode) => UserAction::SetSnippetMode(mode.to_string()),
             ("suggest", query) => UserAction::GetSuggestions(query.to_string()),
             ("add", id) => UserAction::AddSong(id.to_string()),
             ("remove", idx) => UserAction::RemoveSong(idx.parse().unwrap_or(0)),
-            ("start_guessing", _) => UserAction::StartGuessing,
+            ("start_guessing", force) => UserAction::StartGuessing(force.trim() == "force"),
             ("guess", idx) => UserAction::GuessSong(idx.parse().unwrap_or(0)),
+            ("loaded", round) => match round.parse() {
+                Ok(round) => UserAction::Loaded(round),
+                Err(_) => UserAction::InvalidAction,
+            },
             ("leave", _) => UserAction::LeaveGame,
             _ => UserAction::InvalidAction,
         }
@@ -62,6 +96,8 @@ impl From<(&str, &str)> for UserAction {
 pub enum ServerMessage {
     ServerAck,
     Error(String),
+    /// client clock - server clock in ms
+    ClockOffset(i64),
     GameCreated(u16),
     GameNotFound,
     // lobby
@@ -70,15 +106,24 @@ pub enum ServerMessage {
     UserReady(String),
     UserUnready(String),
     GameStartAt(u128),
+    QuestionMode(QuestionMode),
+    SnippetMode(SnippetMode),
     // song selection
     GameStartSelect,
-    Suggestion(Vec<invidious::hidden::SearchItem>),
+    Suggestion(Vec<SearchResult>),
     AddedSong(Song),
+    DownloadStatus(String, DownloadStatus),
     RemovedSong(u32),
+    SelectionProgress(Vec<SelectionProgress>),
     // guessing
     GameStartGuessing,
-    GamePlayAudio(String),
-    GameGuessOptions(Vec<(String, String)>),
+    /// round index and audio token to load ahead of the round
+    GamePreload(u16, String),
+    PreloadStragglers(Vec<String>),
+    /// snippet to play, starting at the given server time in ms
+    GamePlayAudio(Snippet, u128),
+    GameQuestion(QuestionType),
+    GameGuessOptions(Vec<GuessOption>),
 
     LeaderBoard(Vec<(String, usize)>),
     Correct(u8),
@@ -95,8 +140,23 @@ pub enum GameStatus {
 
 #[derive(Clone, Debug)]
 pub enum PlayPhase {
-    SelectingSongs(HashMap<usize, Vec<Song>>),
-    GuessingSongs(SyncSender<(Arc<RwLock<User>>, u8)>), // game thread sender
+    SelectingSongs(SongSelection),
+    GuessingSongs(SyncSender<PlayerInput>), // game thread sender
+}
+
+/// Songs picked by the players, keyed by the address of their `User`
+#[derive(Clone, Debug, Default)]
+pub struct SongSelection {
+    pub songs: HashMap<usize, Vec<Song>>,
+    /// add requests that are still downloading
+    pub pending: HashMap<usize, usize>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SelectionProgress {
+    name: String,
+    pending: usize,
+    finished: usize,
 }
 
 #[derive(Clone)]
@@ -104,6 +164,10 @@ pub struct Game {
     pub id: u16,
     pub players: Vec<Arc<RwLock<User>>>,
     pub state: GameStatus,
+    pub question_mode: QuestionMode,
+    pub snippet_mode: SnippetMode,
+    pub timings: GameTimings,
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Game {
@@ -112,6 +176,10 @@ impl Game {
             id: rand::random(),
             players: Vec::new(),
             state: GameStatus::Lobby(0),
+            question_mode: QuestionMode::default(),
+            snippet_mode: SnippetMode::default(),
+            timings: timings::timings(),
+            clock: clock::real(),
         }
     }
 
@@ -134,12 +202,19 @@ impl Game {
         self.players.iter().for_each(|player| {
             addr.do_send(ServerMessage::UserJoin(player.read().unwrap().name.clone()));
         });
+        addr.do_send(ServerMessage::QuestionMode(self.question_mode));
+        addr.do_send(ServerMessage::SnippetMode(self.snippet_mode));
         self.players.push(user);
     }
 
     fn leave_game(&mut self, user: Arc<RwLock<User>>) -> bool {
         let user_id = user.read().unwrap().id;
 
+        // let the game thread stop waiting for the player
+        if let GameStatus::Playing(PlayPhase::GuessingSongs(tx)) = &self.state {
+            let _ = tx.try_send((user.clone(), RoundInput::Left));
+        }
+
         let name = user.read().unwrap().name.clone();
         self.broadcast_message(ServerMessage::UserLeave(name));
         self.players
@@ -169,21 +244,16 @@ impl Game {
                     return ServerMessage::ServerAck;
                 }
                 // announce game start
-                #[cfg(debug_assertions)]
-                static START_TIMEOUT: Duration = Duration::from_secs(2);
-                #[cfg(not(debug_assertions))]
-                static START_TIMEOUT: Duration = Duration::from_secs(12);
+                let start_countdown = self.timings.start_countdown;
                 self.broadcast_message(ServerMessage::GameStartAt(
-                    (std::time::UNIX_EPOCH
-                        .elapsed()
-                        .expect("system to provide elapsed UNIX time")
-                        + START_TIMEOUT)
-                        .as_millis(),
+                    self.clock.unix_ms() + start_countdown.as_millis(),
                 ));
 
                 let game_id = self.id;
+                let clock = self.clock.clone();
+                let start_at = clock.now() + start_countdown;
                 thread::spawn(move || {
-                    thread::sleep(START_TIMEOUT);
+                    clock.sleep_until(start_at);
                     if let Some(game) = GAMES.write().unwrap().get_mut(&game_id) {
                         if let GameStatus::Lobby(ready_count) = &mut game.state {
                             if (*ready_count as usize) < game.players.len() {
@@ -213,15 +283,80 @@ impl Game {
         }
     }
 
+    fn set_question_mode(&mut self, user: &Arc<RwLock<User>>, mode: &str) -> ServerMessage {
+        if !matches!(self.state, GameStatus::Lobby(_)) {
+            return ServerMessage::Error(
+                "cannot set question mode: game is not in lobby state".into(),
+            );
+        }
+        if !Arc::ptr_eq(user, &self.players[0]) {
+            return ServerMessage::Error("cannot set question mode: you are not the leader".into());
+        }
+        match QuestionMode::try_from(mode) {
+            Ok(mode) => {
+                self.question_mode = mode;
+                self.broadcast_message(ServerMessage::QuestionMode(mode));
+                ServerMessage::ServerAck
+            }
+            Err(_) => ServerMessage::Error(format!("unknown question mode: {}", mode)),
+        }
+    }
+
+    fn set_snippet_mode(&mut self, user: &Arc<RwLock<User>>, mode: &str) -> ServerMessage {
+        if !matches!(self.state, GameStatus::Lobby(_)) {
+            return ServerMessage::Error(
+                "cannot set snippet mode: game is not in lobby state".into(),
+            );
+        }
+        if !Arc::ptr_eq(user, &self.players[0]) {
+            return ServerMessage::Error("cannot set snippet mode: you are not the leader".into());
+        }
+        match SnippetMode::try_from(mode) {
+            Ok(mode) => {
+                self.snippet_mode = mode;
+                self.broadcast_message(ServerMessage::SnippetMode(mode));
+                ServerMessage::ServerAck
+            }
+            Err(_) => ServerMessage::Error(format!("unknown snippet mode: {}", mode)),
+        }
+    }
+
     fn start_game(&mut self) {
         // self.state = GameStatus::Playing(Vec::new(), PlayPhase::SelectingSongs);
         self.set_state(GameStatus::Playing(PlayPhase::SelectingSongs(
-            HashMap::new(),
+            SongSelection::default(),
         )));
         self.broadcast_message(ServerMessage::GameStartSelect);
     }
 
-    fn start_guessing(&mut self, user: Arc<RwLock<User>>) -> Option<()> {
+    fn selection_progress(&self) -> Option<Vec<SelectionProgress>> {
+        let GameStatus::Playing(PlayPhase::SelectingSongs(selection)) = &self.state else {
+            return None;
+        };
+        Some(
+            self.players
+                .iter()
+                .map(|player| {
+                    let player_addr = Arc::as_ptr(player) as usize;
+                    SelectionProgress {
+                        name: player.read().unwrap().name.clone(),
+                        pending: selection.pending.get(&player_addr).copied().unwrap_or(0),
+                        finished: selection.songs.get(&player_addr).map_or(0, Vec::len),
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn broadcast_selection_progress(&self) {
+        if let Some(progress) = self.selection_progress() {
+            self.broadcast_message(ServerMessage::SelectionProgress(progress));
+        }
+    }
+
+    /// Starts guessing once every download of the selection finished.
+    /// With `force` the leader starts right away and pending songs are skipped.
+    fn start_guessing(&mut self, user: Arc<RwLock<User>>, force: bool) -> Option<()> {
         let read_usr = user.read().unwrap();
         let user_addr = read_usr.ws.as_ref()?;
         match &mut self.state {
@@ -232,8 +367,8 @@ impl Game {
                     ));
                     return None;
                 }
-                let songs = match playphase {
-                    PlayPhase::SelectingSongs(songs) => songs,
+                let selection = match playphase {
+                    PlayPhase::SelectingSongs(selection) => selection,
                     _ => {
                         user_addr.do_send(ServerMessage::Error(
                             "cannot start guessing: game is not in song selection state".into(),
@@ -241,11 +376,31 @@ impl Game {
                         return None;
                     }
                 };
-                let (tx, game_handle) = handle_guessing(self.players.clone(), songs);
+                let pending: usize = self
+                    .players
+                    .iter()
+                    .filter_map(|player| selection.pending.get(&(Arc::as_ptr(player) as usize)))
+                    .sum();
+                if pending > 0 && !force {
+                    user_addr.do_send(ServerMessage::Error(format!(
+                        "cannot start guessing: {} downloads pending, use `start_guessing force` to skip them",
+                        pending
+                    )));
+                    return None;
+                }
+                // announced before the game thread starts, so it arrives before the first preload
+                broadcast_users(&self.players, ServerMessage::GameStartGuessing);
+                let (tx, game_handle) = handle_guessing(
+                    self.players.clone(),
+                    &mut selection.songs,
+                    self.question_mode,
+                    self.snippet_mode,
+                    self.timings,
+                    self.clock.clone(),
+                );
                 handle_game_end(game_handle, self.id);
 
                 *playphase = PlayPhase::GuessingSongs(tx);
-                self.broadcast_message(ServerMessage::GameStartGuessing);
             }
             _ => user_addr.do_send(ServerMessage::Error(
                 "cannot start guessing: game is not in song selection state".into(),
@@ -255,6 +410,14 @@ impl Game {
     }
 }
 
+/// Runs the game on `clock` from now on, for tests that step through the timing rules
+#[cfg(test)]
+pub fn set_game_clock(game_id: u16, clock: Arc<dyn Clock>) {
+    if let Some(game) = GAMES.write().unwrap().get_mut(&game_id) {
+        game.clock = clock;
+    }
+}
+
 pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()> {
     let user_addr = user.read().unwrap().ws.as_ref()?.to_owned();
 
@@ -270,11 +433,18 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
         let user_room_id = user.read().unwrap().game_id?;
         if games.get_mut(&user_room_id)?.leave_game(user.clone()) {
             games.remove(&user_room_id);
+            song_cache::unpin_game(user_room_id);
         }
         Some(())
     };
 
     match action {
+        UserAction::ClockPong(sent_ms, client_ms) => {
+            let received_ms = server_time_ms();
+            let mut write_user = user.write().unwrap();
+            write_user.clock.record(sent_ms, client_ms, received_ms);
+            send_msg(ServerMessage::ClockOffset(write_user.clock.offset_ms()));
+        }
         UserAction::SetUsername(name) => {
             user.write().unwrap().name = name.trim_matches('"').to_string();
             ack();
@@ -325,6 +495,32 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
                     .do_send(ServerMessage::Error("cannot unready: not in a game".into())),
             };
         }
+        UserAction::SetQuestionMode(mode) => {
+            let game_id = user.read().unwrap().game_id;
+            match game_id {
+                Some(game_id) => {
+                    let mut games = GAMES.write().unwrap();
+                    let game = games.get_mut(&game_id)?;
+                    send_msg(game.set_question_mode(&user, &mode));
+                }
+                None => send_msg(ServerMessage::Error(
+                    "cannot set question mode: not in a game".into(),
+                )),
+            }
+        }
+        UserAction::SetSnippetMode(mode) => {
+            let game_id = user.read().unwrap().game_id;
+            match game_id {
+                Some(game_id) => {
+                    let mut games = GAMES.write().unwrap();
+                    let game = games.get_mut(&game_id)?;
+                    send_msg(game.set_snippet_mode(&user, &mode));
+                }
+                None => send_msg(ServerMessage::Error(
+                    "cannot set snippet mode: not in a game".into(),
+                )),
+            }
+        }
         UserAction::LeaveGame => {
             leave_current();
             // DEADLOCK:
@@ -338,71 +534,73 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
         UserAction::GetSuggestions(query) => {
             send_msg(match music_handler::get_suggestions(&query) {
                 Ok(songs) => ServerMessage::Suggestion(songs),
-                Err(err) => ServerMessage::Error(err.to_string()),
+                Err(err) => ServerMessage::Error(format!("{:?}", err)),
             });
         }
         UserAction::AddSong(source_id) => {
-            let read_user = user.read().unwrap();
-            if read_user.game_id.is_none() {
+            let Some(game_id) = user.read().unwrap().game_id else {
                 send_msg(ServerMessage::Error(
                     "cannot add song: not in a game".into(),
                 ));
                 return None;
-            }
-            let games = GAMES.read().unwrap();
-            let game = games.get(&read_user.game_id?)?;
-            if !matches!(
-                &game.state,
-                GameStatus::Playing(PlayPhase::SelectingSongs(_))
-            ) {
-                send_msg(ServerMessage::Error(
-                    "cannot add song: game is not in song selection state".into(),
-                ));
-                return None;
-            }
-            drop(read_user);
-            drop(games); // unlock during download
+            };
+            {
+                let mut games = GAMES.write().unwrap();
+                let game = games.get_mut(&game_id)?;
+                match &mut game.state {
+                    GameStatus::Playing(PlayPhase::SelectingSongs(selection)) => {
+                        *selection.pending.entry(user_ptr_addr).or_default() += 1;
+                    }
+                    _ => {
+                        send_msg(ServerMessage::Error(
+                            "cannot add song: game is not in song selection state".into(),
+                        ));
+                        return None;
+                    }
+                }
+                game.broadcast_selection_progress();
+            } // unlock during download
 
-            let user_c = user.clone();
             let cloned_addr = user_addr.clone();
+            let progress_addr = user_addr.clone();
+            let requester = DownloadRequester {
+                user_id: user.read().unwrap().id,
+                listener: Some(Arc::new(move |id: &str, status: &DownloadStatus| {
+                    progress_addr
+                        .do_send(ServerMessage::DownloadStatus(id.to_owned(), status.clone()))
+                })),
+            };
             thread::spawn(move || {
-                let read_user = user_c.read().unwrap();
+                let song_or_songs =
+                    music_handler::get_one_or_more_songs_from_id(&source_id, requester);
+
+                let mut games = GAMES.write().unwrap();
+                let Some(game) = games.get_mut(&game_id) else {
+                    return;
+                };
+                let mut added = vec![];
+                let selection = match &mut game.state {
+                    GameStatus::Playing(PlayPhase::SelectingSongs(selection)) => selection,
+                    _ => {
+                        println!("Game started before song download could finish");
+                        cloned_addr.do_send(ServerMessage::Error(format!(
+                            "skipped {}: guessing started before the download finished",
+                            source_id
+                        )));
+                        return;
+                    }
+                };
+                if let Some(pending) = selection.pending.get_mut(&user_ptr_addr) {
+                    *pending = pending.saturating_sub(1);
+                }
 
-                let song_or_songs = music_handler::get_one_or_more_songs_from_id(&source_id);
                 match song_or_songs {
                     Ok(song_or_songs) => {
-                        let mut games = GAMES.write().unwrap();
-                        let game = games
-                            .get_mut(match &read_user.game_id {
-                                Some(id) => id,
-                                None => {
-                                    return;
-                                }
-                            })
-                            .unwrap();
-                        drop(read_user);
-
-                        let user_songs = match &mut game.state {
-                            GameStatus::Playing(game_songs) => match game_songs {
-                                PlayPhase::SelectingSongs(user_songs) => {
-                                    match user_songs.get_mut(&user_ptr_addr) {
-                                        Some(user_songs) => user_songs,
-                                        None => {
-                                            user_songs.insert(user_ptr_addr, Vec::new());
-                                            user_songs.get_mut(&user_ptr_addr).unwrap()
-                                        }
-                                    }
-                                }
-                                _ => return,
-                            },
-                            _ => {
-                                println!("Game started before song download could finish");
-                                return;
-                            }
-                        };
-
+                        let user_songs = selection.songs.entry(user_ptr_addr).or_default();
                         match song_or_songs {
                             music_handler::OneOrMoreSongs::One(song) => {
+                                song_cache::pin(game_id, &song.id);
+                                added.push(song.id.clone());
                                 user_songs.push(song);
                                 cloned_addr.do_send(ServerMessage::AddedSong(
                                     user_songs.last().unwrap().clone(),
@@ -410,6 +608,8 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
                             }
                             music_handler::OneOrMoreSongs::More(songs) => {
                                 songs.iter().for_each(|song| {
+                                    song_cache::pin(game_id, &song.id);
+                                    added.push(song.id.clone());
                                     cloned_addr.do_send(ServerMessage::AddedSong(song.clone()))
                                 });
                                 user_songs.extend(songs);
@@ -417,13 +617,12 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
                         }
                     }
                     Err(err) => {
-                        read_user
-                            .ws
-                            .as_ref()
-                            .unwrap()
-                            .do_send(ServerMessage::Error(format!("{:#?}", err)));
+                        cloned_addr.do_send(ServerMessage::Error(format!("{:#?}", err)));
                     }
                 }
+                game.broadcast_selection_progress();
+                drop(games);
+                prefetch::record_adds(&added);
             });
             ack();
         }
@@ -433,7 +632,9 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
             let game = games.get_mut(&read_user.game_id?)?;
             let user_songs = match &mut game.state {
                 GameStatus::Playing(phase) => match phase {
-                    PlayPhase::SelectingSongs(user_songs) => user_songs.get_mut(&user_ptr_addr)?,
+                    PlayPhase::SelectingSongs(selection) => {
+                        selection.songs.get_mut(&user_ptr_addr)?
+                    }
                     _ => return None,
                 },
                 _ => {
@@ -451,8 +652,9 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
             }
             user_songs.remove(idx as usize);
             send_msg(ServerMessage::RemovedSong(idx));
+            game.broadcast_selection_progress();
         }
-        UserAction::StartGuessing => {
+        UserAction::StartGuessing(force) => {
             let read_user = user.read().unwrap();
             if read_user.game_id.is_none() {
                 send_msg(ServerMessage::Error(
@@ -462,7 +664,7 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
             }
             let mut games = GAMES.write().unwrap();
             let game = games.get_mut(&read_user.game_id.unwrap()).unwrap();
-            game.start_guessing(user.clone());
+            game.start_guessing(user.clone(), force);
         }
         UserAction::GuessSong(idx) => {
             let read_user = user.read().unwrap();
@@ -476,7 +678,7 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
             let game = games.get(&read_user.game_id.unwrap()).unwrap();
             match &game.state {
                 GameStatus::Playing(PlayPhase::GuessingSongs(tx)) => {
-                    tx.send((user.clone(), idx)).unwrap();
+                    let _ = tx.send((user.clone(), RoundInput::Guess(idx)));
                 }
                 _ => {
                     send_msg(ServerMessage::Error(
@@ -485,6 +687,13 @@ pub fn handle_user_msg(action: UserAction, user: Arc<RwLock<User>>) -> Option<()
                 }
             }
         }
+        UserAction::Loaded(round) => {
+            let game_id = user.read().unwrap().game_id?;
+            let games = GAMES.read().unwrap();
+            if let GameStatus::Playing(PlayPhase::GuessingSongs(tx)) = &games.get(&game_id)?.state {
+                let _ = tx.send((user.clone(), RoundInput::Loaded(round)));
+            }
+        }
         _ => send_msg(ServerMessage::Error("Invalid Action".to_string())),
     };
     Some(())
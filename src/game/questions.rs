@@ -0,0 +1,192 @@
+use rand::{seq::SliceRandom, thread_rng};
+use serde::Serialize;
+
+use crate::model::song::Song;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuestionType {
+    TitleArtist,
+    Title,
+    Artist,
+    Year,
+    Decade,
+}
+
+impl QuestionType {
+    const ALL: [QuestionType; 5] = [
+        QuestionType::TitleArtist,
+        QuestionType::Title,
+        QuestionType::Artist,
+        QuestionType::Year,
+        QuestionType::Decade,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuestionType::TitleArtist => "title_artist",
+            QuestionType::Title => "title",
+            QuestionType::Artist => "artist",
+            QuestionType::Year => "year",
+            QuestionType::Decade => "decade",
+        }
+    }
+
+    /// year questions need to know when the song was released
+    fn can_ask(&self, song: &Song) -> bool {
+        match self {
+            QuestionType::Year | QuestionType::Decade => song.year().is_some(),
+            _ => true,
+        }
+    }
+
+    /// The answer to this question for `song`
+    pub fn answer(&self, song: &Song) -> GuessOption {
+        match self {
+            QuestionType::TitleArtist => {
+                GuessOption::TitleArtist(song.title.clone(), song.artist.clone())
+            }
+            QuestionType::Title => GuessOption::Single(song.title.clone()),
+            QuestionType::Artist => GuessOption::Single(song.artist.clone()),
+            QuestionType::Year => GuessOption::Single(song.year().unwrap_or_default().to_string()),
+            QuestionType::Decade => {
+                GuessOption::Single(decade_name(song.year().unwrap_or_default()))
+            }
+        }
+    }
+}
+
+pub fn decade_name(year: i32) -> String {
+    format!("{}s", year - year.rem_euclid(10))
+}
+
+/// Which question types are asked during a game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuestionMode {
+    Fixed(QuestionType),
+    /// a random question type per round
+    Mixed,
+}
+
+impl Default for QuestionMode {
+    fn default() -> Self {
+        QuestionMode::Fixed(QuestionType::TitleArtist)
+    }
+}
+
+impl TryFrom<&str> for QuestionMode {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim().trim_matches('"');
+        if value == "mixed" {
+            return Ok(QuestionMode::Mixed);
+        }
+        QuestionType::ALL
+            .into_iter()
+            .find(|question| question.name() == value)
+            .map(QuestionMode::Fixed)
+            .ok_or(())
+    }
+}
+
+impl QuestionMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuestionMode::Fixed(question) => question.name(),
+            QuestionMode::Mixed => "mixed",
+        }
+    }
+
+    /// Picks the question for a round, falling back to title + artist
+    /// when the song lacks the metadata needed for the question
+    pub fn question_for(&self, song: &Song) -> QuestionType {
+        let question = match self {
+            QuestionMode::Fixed(question) => *question,
+            QuestionMode::Mixed => *QuestionType::ALL
+                .choose(&mut thread_rng())
+                .unwrap_or(&QuestionType::TitleArtist),
+        };
+        if question.can_ask(song) {
+            question
+        } else {
+            QuestionType::TitleArtist
+        }
+    }
+}
+
+/// A single answer option, serialized as `["title", "artist"]` or `"value"`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum GuessOption {
+    TitleArtist(String, String),
+    Single(String),
+}
+
+impl GuessOption {
+    /// normalized form used to detect duplicate options
+    pub fn key(&self) -> String {
+        let normalize = |s: &str| s.trim().to_lowercase();
+        match self {
+            GuessOption::TitleArtist(title, artist) => {
+                format!("{}\n{}", normalize(title), normalize(artist))
+            }
+            GuessOption::Single(value) => normalize(value),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            GuessOption::TitleArtist(title, _) => title.trim().is_empty(),
+            GuessOption::Single(value) => value.trim().is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(published: u64, release_year: Option<i32>) -> Song {
+        Song {
+            id: "test:0".to_string(),
+            title: "Lyric Video".to_string(),
+            artist: "Someone".to_string(),
+            length: 0,
+            published,
+            album: None,
+            release_year,
+        }
+    }
+
+    #[test]
+    fn year_questions_need_the_release_year() {
+        // uploaded in 2020, the upload date says nothing about the release
+        let reupload = song(1_600_000_000, None);
+        for question in [QuestionType::Year, QuestionType::Decade] {
+            let mode = QuestionMode::Fixed(question);
+            assert_eq!(mode.question_for(&reupload), QuestionType::TitleArtist);
+        }
+
+        let released = song(1_600_000_000, Some(1987));
+        let mode = QuestionMode::Fixed(QuestionType::Year);
+        assert_eq!(mode.question_for(&released), QuestionType::Year);
+        assert_eq!(
+            QuestionType::Year.answer(&released),
+            GuessOption::Single("1987".to_string())
+        );
+        assert_eq!(
+            QuestionType::Decade.answer(&released),
+            GuessOption::Single("1980s".to_string())
+        );
+    }
+
+    #[test]
+    fn modes_are_parsed_by_name() {
+        assert_eq!(QuestionMode::try_from("mixed"), Ok(QuestionMode::Mixed));
+        assert_eq!(
+            QuestionMode::try_from("\"decade\""),
+            Ok(QuestionMode::Fixed(QuestionType::Decade))
+        );
+        assert!(QuestionMode::try_from("lyrics").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+
+use crate::{
+    audio,
+    model::song::Song,
+    music_handler::{self, song_cache},
+};
+
+pub const SNIPPET_LENGTH: Duration = Duration::from_secs(30);
+/// serve a pre-cut clip instead of the whole file, so clients can't scrub through the song
+const PRE_CUT_CLIPS: bool = true;
+pub const CLIPS_DIR: &str = "clips";
+
+/// Which part of a song is played in a round
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SnippetMode {
+    #[default]
+    Full,
+    Random,
+    SkipIntro,
+    Chorus,
+}
+
+impl SnippetMode {
+    const ALL: [SnippetMode; 4] = [
+        SnippetMode::Full,
+        SnippetMode::Random,
+        SnippetMode::SkipIntro,
+        SnippetMode::Chorus,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnippetMode::Full => "full",
+            SnippetMode::Random => "random",
+            SnippetMode::SkipIntro => "skip_intro",
+            SnippetMode::Chorus => "chorus",
+        }
+    }
+}
+
+impl TryFrom<&str> for SnippetMode {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim().trim_matches('"');
+        SnippetMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == value)
+            .ok_or(())
+    }
+}
+
+/// The part of a file clients should play.
+/// `file` is relative to the songs directory until it is swapped for an audio token.
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    pub file: String,
+    pub offset: Duration,
+    /// zero when the length of the song is unknown, play until the end then
+    pub length: Duration,
+}
+
+/// Chooses the start offset (in seconds) of the snippet
+fn choose_offset(mode: SnippetMode, song_secs: u64, snippet_secs: u64, profile: &[f32]) -> u64 {
+    let latest = song_secs.saturating_sub(snippet_secs);
+    // intros are roughly the first tenth of a song
+    let skip_intro = || (audio::leading_silence(profile) as u64).max(song_secs / 10);
+    let offset = match mode {
+        SnippetMode::Full => 0,
+        SnippetMode::Random => thread_rng().gen_range(0..=latest),
+        SnippetMode::SkipIntro => skip_intro(),
+        SnippetMode::Chorus => {
+            audio::loudest_window(profile, snippet_secs as usize, (song_secs / 5) as usize)
+                .map(|start| start as u64)
+                .unwrap_or_else(skip_intro)
+        }
+    };
+    offset.min(latest)
+}
+
+/// Picks the part of `song` to play this round according to `mode`
+pub fn choose_snippet(mode: SnippetMode, song: &Song) -> Snippet {
+    let file = music_handler::playable_file(&song.id);
+    let song_path = music_handler::songs_dir().join(&file);
+    let profile = match mode {
+        SnippetMode::SkipIntro | SnippetMode::Chorus => {
+            audio::energy_profile(&song_path).unwrap_or_default()
+        }
+        _ => vec![],
+    };
+    let song_secs = match profile.len() {
+        0 => song.length as u64,
+        analyzed => analyzed as u64,
+    };
+
+    if mode == SnippetMode::Full || song_secs == 0 {
+        return Snippet {
+            file,
+            offset: Duration::ZERO,
+            length: Duration::from_secs(song_secs),
+        };
+    }
+
+    let snippet_secs = SNIPPET_LENGTH.as_secs().min(song_secs);
+    let offset = Duration::from_secs(choose_offset(mode, song_secs, snippet_secs, &profile));
+    let length = Duration::from_secs(snippet_secs);
+
+    if PRE_CUT_CLIPS {
+        let clip_name = format!("{}-{}-{}", file, offset.as_secs(), length.as_secs());
+        let clips_dir = music_handler::songs_dir().join(CLIPS_DIR);
+        let clip_path = clips_dir.join(&clip_name);
+        let reused = clip_path.exists();
+        if reused {
+            song_cache::touch_clip(&clip_path);
+        }
+        if reused
+            || (std::fs::create_dir_all(&clips_dir).is_ok()
+                && audio::cut_clip(&song_path, &clip_path, offset, length))
+        {
+            return Snippet {
+                file: format!("{}/{}", CLIPS_DIR, clip_name),
+                offset: Duration::ZERO,
+                length,
+            };
+        }
+    }
+
+    Snippet {
+        file,
+        offset,
+        length,
+    }
+}
+
+/// Cuts the clips of the snippet modes that always pick the same part of a song,
+/// so rounds using them don't have to wait for ffmpeg
+pub fn pre_cut(song: &Song) {
+    if PRE_CUT_CLIPS {
+        choose_snippet(SnippetMode::SkipIntro, song);
+        choose_snippet(SnippetMode::Chorus, song);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{sync::RwLock, time::Duration};
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::config::duration_ms;
+
+/// The delays of the game flow. Games copy the current timings when they are created,
+/// they come from the `[game]` section of the config, in milliseconds.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameTimings {
+    /// between everyone being ready and the song selection
+    #[serde(rename = "start_countdown_ms", deserialize_with = "duration_ms")]
+    pub start_countdown: Duration,
+    /// minimum time between announcing a song and playing it
+    #[serde(rename = "play_start_lead_ms", deserialize_with = "duration_ms")]
+    pub play_start_lead: Duration,
+    /// how long to wait for every client to load the audio of a round
+    #[serde(rename = "preload_timeout_ms", deserialize_with = "duration_ms")]
+    pub preload_timeout: Duration,
+    #[serde(rename = "guess_timeout_ms", deserialize_with = "duration_ms")]
+    pub guess_timeout: Duration,
+    /// how long the correct answer is shown before the leaderboard
+    #[serde(rename = "reveal_ms", deserialize_with = "duration_ms")]
+    pub reveal: Duration,
+    #[serde(rename = "leaderboard_ms", deserialize_with = "duration_ms")]
+    pub leaderboard: Duration,
+    /// after the last round, before going back to the lobby
+    #[serde(rename = "game_end_ms", deserialize_with = "duration_ms")]
+    pub game_end: Duration,
+}
+
+impl Default for GameTimings {
+    fn default() -> Self {
+        Self {
+            start_countdown: Duration::from_secs(12),
+            play_start_lead: Duration::from_millis(500),
+            preload_timeout: Duration::from_secs(10),
+            guess_timeout: Duration::from_secs(180),
+            reveal: Duration::from_secs(2),
+            leaderboard: Duration::from_secs(5),
+            game_end: Duration::from_secs(10),
+        }
+    }
+}
+
+static TIMINGS: Lazy<RwLock<GameTimings>> = Lazy::new(|| RwLock::new(GameTimings::default()));
+
+/// timings new games are created with
+pub fn timings() -> GameTimings {
+    *TIMINGS.read().unwrap()
+}
+
+pub fn set_timings(timings: GameTimings) {
+    *TIMINGS.write().unwrap() = timings;
+}
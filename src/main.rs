@@ -1,18 +1,30 @@
+mod audio;
+mod cli;
+mod config;
 mod game;
 mod model;
 mod music_handler;
+#[cfg(test)]
+mod tests;
 
 use std::{
+    path::PathBuf,
+    process::ExitCode,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
 
 use actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler};
+use actix_files::NamedFile;
 use actix_web::{get, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use game::{ServerMessage, UserAction};
 
-use model::{search_result::SearchResult, user::User};
+use model::{clock_sync::ClockSync, user::User};
+use music_handler::song_cache;
 
 pub struct UserSocket {
     pub user: Arc<RwLock<User>>,
@@ -20,7 +32,7 @@ pub struct UserSocket {
 }
 
 impl UserSocket {
-    pub fn new() -> Self {
+    fn new() -> Self {
         Self {
             hb: Instant::now(),
             user: Arc::new(RwLock::new(User {
@@ -28,6 +40,7 @@ impl UserSocket {
                 name: "User ".to_string() + rand::random::<u8>().to_string().as_str(),
                 game_id: None,
                 ws: None,
+                clock: ClockSync::default(),
             })),
         }
     }
@@ -48,6 +61,21 @@ impl UserSocket {
             ctx.ping(b"");
         });
     }
+
+    /// periodically measure the client's clock offset, see `ClockSync`
+    fn clock_sync(&self, ctx: &mut <Self as Actor>::Context) {
+        const CLOCK_SYNC_INTERVAL: std::time::Duration = Duration::from_secs(5);
+        ctx.text(format!(
+            "clock_ping {}",
+            self.user.write().unwrap().clock.ping()
+        ));
+        ctx.run_interval(CLOCK_SYNC_INTERVAL, |act, ctx| {
+            ctx.text(format!(
+                "clock_ping {}",
+                act.user.write().unwrap().clock.ping()
+            ));
+        });
+    }
 }
 
 impl Actor for UserSocket {
@@ -55,13 +83,18 @@ impl Actor for UserSocket {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        self.clock_sync(ctx);
         ctx.text(format!(
             "GTS v{} | {} | under {}",
             env!("CARGO_PKG_VERSION"),
             env!("CARGO_PKG_REPOSITORY"),
             env!("CARGO_PKG_LICENSE")
         ));
-        ctx.text(format!("song_route {}", SONGS_ROUTE));
+        ctx.text(format!(
+            "song_route {}",
+            config::config().server.songs_route
+        ));
+        ctx.text(format!("audio_route {}", AUDIO_ROUTE));
         self.user.write().unwrap().ws = Some(ctx.address());
     }
 
@@ -89,24 +122,39 @@ impl Handler<ServerMessage> for UserSocket {
             ServerMessage::UserReady(name) => format!("user_ready \"{}\"", name),
             ServerMessage::UserUnready(name) => format!("user_unready \"{}\"", name),
             ServerMessage::GameStartAt(time) => format!("game_start_at {}", time),
+            ServerMessage::QuestionMode(mode) => format!("question_mode {}", mode.name()),
+            ServerMessage::SnippetMode(mode) => format!("snippet_mode {}", mode.name()),
             ServerMessage::GameStartSelect => "game_start_select".to_string(),
-            ServerMessage::Suggestion(songs) => format!(
-                "suggestions {}",
-                serde_json::to_string(
-                    &songs
-                        .into_iter()
-                        .map(SearchResult::from)
-                        .collect::<Vec<_>>()
-                )
-                .unwrap()
-            ),
+            ServerMessage::Suggestion(results) => {
+                format!("suggestions {}", serde_json::to_string(&results).unwrap())
+            }
             ServerMessage::AddedSong(song) => format!(
                 "added_song {}",
                 serde_json::to_string(&(song.title, song.artist)).unwrap()
             ),
+            ServerMessage::DownloadStatus(id, status) => {
+                format!("download_status {} {}", id, status.name())
+            }
             ServerMessage::RemovedSong(song_idx) => format!("removed_song {}", song_idx),
+            ServerMessage::SelectionProgress(progress) => format!(
+                "selection_progress {}",
+                serde_json::to_string(&progress).unwrap()
+            ),
             ServerMessage::GameStartGuessing => "game_start_guessing".to_string(),
-            ServerMessage::GamePlayAudio(id) => format!("game_play_audio {}", id),
+            ServerMessage::GamePreload(round, token) => format!("game_preload {} {}", round, token),
+            ServerMessage::PreloadStragglers(names) => format!(
+                "preload_stragglers {}",
+                serde_json::to_string(&names).unwrap()
+            ),
+            ServerMessage::ClockOffset(offset) => format!("clock_offset {}", offset),
+            ServerMessage::GamePlayAudio(snippet, start_at) => format!(
+                "game_play_audio {} {} {} {}",
+                snippet.file,
+                snippet.offset.as_millis(),
+                snippet.length.as_millis(),
+                start_at
+            ),
+            ServerMessage::GameQuestion(question) => format!("game_question {}", question.name()),
             ServerMessage::GameGuessOptions(options) => format!(
                 "game_guess_options {}",
                 serde_json::to_string(&options).unwrap()
@@ -134,14 +182,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for UserSocket {
             Ok(ws::Message::Pong(_)) => {
                 self.hb = Instant::now();
             }
-            Ok(ws::Message::Text(text)) => match text.to_string().trim().split_once(' ') {
-                Some((action, body)) => {
-                    self.hb = Instant::now();
-                    let _ =
-                        game::handle_user_msg(UserAction::from((action, body)), self.user.clone());
+            Ok(ws::Message::Text(text)) => {
+                let text = text.trim();
+                // actions without a body, like `new`, come without the space
+                let (action, body) = text.split_once(' ').unwrap_or((text, ""));
+                if action.is_empty() {
+                    ctx.text("?");
+                    return;
                 }
-                _ => ctx.text("?"),
-            },
+                self.hb = Instant::now();
+                let _ = game::handle_user_msg(UserAction::from((action, body)), self.user.clone());
+            }
             _ => {
                 ctx.close(Some(CloseReason {
                     code: CloseCode::Invalid,
@@ -159,18 +210,67 @@ async fn index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, E
     ws::start(UserSocket::new(), &req, stream)
 }
 
-const SONGS_ROUTE: &str = "/songs";
+/// round audio, served under the opaque tokens of `game::audio_tokens`
+#[get("/audio/{token}")]
+async fn round_audio(token: web::Path<String>) -> Result<NamedFile, Error> {
+    let path = game::audio_tokens::resolve(&token)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("unknown or expired audio token"))?;
+    // the default `Content-Disposition` would name the file, and with it the song
+    Ok(NamedFile::open(path)?.disable_content_disposition())
+}
+
+/// the songs directory, without the files that would give away songs of running games
+async fn song_file(file: web::Path<String>) -> Result<NamedFile, Error> {
+    let file = PathBuf::from(file.into_inner());
+    if !song_cache::is_servable(&file) {
+        return Err(actix_web::error::ErrorNotFound("not found"));
+    }
+    Ok(NamedFile::open(music_handler::songs_dir().join(file))?.disable_content_disposition())
+}
+
+const AUDIO_ROUTE: &str = "/audio";
+
+fn configure_app(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(round_audio).route(
+        &format!("{}/{{file:.*}}", config::config().server.songs_route),
+        web::get().to(song_file),
+    );
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("[CONFIG] {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    game::timings::set_timings(config.game);
+    config::init(config);
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => match serve() {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("[SERVER] {}", err);
+                ExitCode::FAILURE
+            }
+        },
+        command => cli::run(command),
+    }
+}
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    music_handler::start_instance_finder();
+fn serve() -> std::io::Result<()> {
+    music_handler::start_sources();
+    music_handler::download_queue::start_download_workers();
+    music_handler::song_cache::start_sweeper();
+    music_handler::prefetch::start();
 
-    HttpServer::new(|| {
-        App::new()
-            .service(index)
-            .service(actix_files::Files::new(SONGS_ROUTE, "./songs_cache"))
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    let server = &config::config().server;
+    actix_web::rt::System::new().block_on(
+        HttpServer::new(|| App::new().configure(configure_app))
+            .bind((server.bind.as_str(), server.port))?
+            .run(),
+    )
 }
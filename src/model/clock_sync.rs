@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+/// how many ping/pong samples are kept per client
+const SAMPLE_COUNT: usize = 8;
+
+/// Current server time in milliseconds since the UNIX epoch
+pub fn server_time_ms() -> u128 {
+    std::time::UNIX_EPOCH
+        .elapsed()
+        .expect("system to provide elapsed UNIX time")
+        .as_millis()
+}
+
+/// Estimate of a client's clock, from `clock_ping`/`clock_pong` round trips
+#[derive(Clone, Debug, Default)]
+pub struct ClockSync {
+    /// (round trip time, client clock - server clock) in ms
+    samples: VecDeque<(u64, i64)>,
+    /// server times of the pings that weren't answered yet
+    pending: VecDeque<u128>,
+}
+
+impl ClockSync {
+    /// The server time to send in a `clock_ping`, only answers to these are recorded
+    pub fn ping(&mut self) -> u128 {
+        let sent_ms = server_time_ms();
+        if self.pending.len() == SAMPLE_COUNT {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(sent_ms);
+        sent_ms
+    }
+
+    /// Records a round trip: the ping was sent at `sent_ms` server time, the client
+    /// answered at `client_ms` client time and the answer arrived at `received_ms`.
+    /// Answers to pings that weren't sent, or were answered already, are ignored,
+    /// a client could make its latency up otherwise.
+    pub fn record(&mut self, sent_ms: u128, client_ms: u128, received_ms: u128) {
+        let Some(idx) = self.pending.iter().position(|pending| *pending == sent_ms) else {
+            return;
+        };
+        self.pending.remove(idx);
+        if received_ms < sent_ms {
+            return;
+        }
+        let rtt = (received_ms - sent_ms) as u64;
+        let offset = client_ms as i64 - (sent_ms as i64 + rtt as i64 / 2);
+        if self.samples.len() == SAMPLE_COUNT {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, offset));
+    }
+
+    /// the sample with the fastest round trip is the most accurate one
+    fn best_sample(&self) -> Option<&(u64, i64)> {
+        self.samples.iter().min_by_key(|(rtt, _)| *rtt)
+    }
+
+    /// client clock - server clock in ms, 0 until the first round trip
+    pub fn offset_ms(&self) -> i64 {
+        self.best_sample().map(|(_, offset)| *offset).unwrap_or(0)
+    }
+
+    /// estimated one way latency in ms
+    pub fn latency_ms(&self) -> u64 {
+        self.best_sample().map(|(rtt, _)| rtt / 2).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_answers_to_sent_pings_are_recorded() {
+        let mut clock = ClockSync::default();
+        clock.record(0, 0, server_time_ms());
+        assert_eq!(clock.latency_ms(), 0);
+
+        let sent_ms = clock.ping();
+        clock.record(sent_ms, sent_ms + 1000, sent_ms + 40);
+        assert_eq!(clock.latency_ms(), 20);
+        assert_eq!(clock.offset_ms(), 980);
+
+        // the same ping can't be answered twice
+        clock.record(sent_ms, sent_ms, sent_ms + 10);
+        assert_eq!(clock.latency_ms(), 20);
+    }
+}
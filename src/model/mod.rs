@@ -1,3 +1,4 @@
+pub mod clock_sync;
 pub mod search_result;
 pub mod song;
 pub mod user;
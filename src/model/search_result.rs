@@ -1,11 +1,11 @@
 use invidious::hidden::SearchItem;
 use serde::Serialize;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Debug)]
 pub struct SearchResult {
-    name: String,
-    id: String,
-    r#type: String,
+    pub name: String,
+    pub id: String,
+    pub r#type: String,
 }
 
 impl From<SearchItem> for SearchResult {
@@ -1,30 +1,96 @@
-use std::string::String;
+use std::{string::String, sync::Arc};
 
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+/// Cloneable, so every requester of a shared download gets the same error
+#[derive(Clone, Debug)]
+#[allow(dead_code)] // fields are only read through the Debug output sent to clients
 pub enum GettingSongError {
-    ReqwestErr(reqwest::Error),
-    InvidiousErr(invidious::InvidiousError),
-    DownloadFailed(std::io::Error),
+    ReqwestErr(Arc<reqwest::Error>),
+    /// the message of an `invidious::InvidiousError`, which can't be sent between threads
+    InvidiousErr(String),
+    DownloadFailed(Arc<std::io::Error>),
+    /// yt-dlp is not installed or not in PATH
+    YtDlpMissing,
+    GeoBlocked,
+    AgeRestricted,
+    /// larger than the maximum file size
+    TooLarge,
+    /// private, removed, unknown to every source or otherwise not available
+    Unavailable,
+    /// yt-dlp failed for an unknown reason, with the last line it logged
+    YtDlpFailed(String),
     OtherError,
 }
 
 impl From<reqwest::Error> for GettingSongError {
     fn from(value: reqwest::Error) -> Self {
-        GettingSongError::ReqwestErr(value)
+        GettingSongError::ReqwestErr(Arc::new(value))
     }
 }
 
 impl From<invidious::InvidiousError> for GettingSongError {
     fn from(value: invidious::InvidiousError) -> Self {
-        GettingSongError::InvidiousErr(value)
+        GettingSongError::InvidiousErr(value.to_string())
     }
 }
 
-#[derive(Clone, Debug)]
+impl From<std::io::Error> for GettingSongError {
+    fn from(value: std::io::Error) -> Self {
+        GettingSongError::DownloadFailed(Arc::new(value))
+    }
+}
+
+/// Serializable to keep the metadata of downloaded songs across restarts
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Song {
     pub id: String,
     pub title: String,
     pub artist: String,
+    /// length in seconds
+    pub length: u32,
+    /// upload date as UNIX timestamp, 0 if unknown. Not the release date,
+    /// re-uploads and lyric videos are often years younger than the song.
+    pub published: u64,
+    pub album: Option<String>,
+    /// from the album or tags, only sources that know it set it
+    #[serde(default)]
+    pub release_year: Option<i32>,
+}
+
+impl Song {
+    /// The year the song was released, year questions are only asked when it's known
+    pub fn year(&self) -> Option<i32> {
+        self.release_year
+    }
+}
+
+/// Calendar year of a UNIX timestamp (seconds)
+pub fn year_from_unix(secs: u64) -> i32 {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_idx = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    (if month_idx >= 10 { year + 1 } else { year }) as i32
+}
+
+impl From<&invidious::CommonVideo> for Song {
+    fn from(video: &invidious::CommonVideo) -> Self {
+        Self {
+            id: video.id.clone(),
+            title: video.title.clone(),
+            artist: video.author.clone(),
+            length: video.length,
+            published: video.published,
+            album: None,
+            release_year: None,
+        }
+    }
 }
 
 impl PartialEq for Song {
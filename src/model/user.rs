@@ -2,12 +2,15 @@ use actix::Addr;
 
 use crate::UserSocket;
 
+use super::clock_sync::ClockSync;
+
 #[derive(Clone, Debug)]
 pub struct User {
     pub id: u32,
     pub name: String,
     pub game_id: Option<u16>,
     pub ws: Option<Addr<UserSocket>>,
+    pub clock: ClockSync,
 }
 
 impl PartialEq for User {
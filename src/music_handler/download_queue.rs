@@ -0,0 +1,262 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use once_cell::sync::Lazy;
+
+use crate::model::song::{GettingSongError, Song};
+
+use super::download_song_from_id;
+
+/// how many songs are downloaded at the same time
+const WORKER_COUNT: usize = 3;
+/// how many workers may download background songs, the rest is kept free for players
+const BACKGROUND_WORKERS: usize = 1;
+
+#[derive(Clone, Debug)]
+pub enum DownloadStatus {
+    /// waiting for a free worker, with the number of downloads ahead of it
+    Queued(usize),
+    Downloading,
+    Done,
+    Failed(String),
+}
+
+impl DownloadStatus {
+    pub fn name(&self) -> String {
+        match self {
+            DownloadStatus::Queued(ahead) => format!("queued {}", ahead),
+            DownloadStatus::Downloading => "downloading".to_string(),
+            DownloadStatus::Done => "done".to_string(),
+            DownloadStatus::Failed(reason) => format!("failed {}", reason),
+        }
+    }
+}
+
+/// Gets told about status changes of a download, with the video ID
+pub type ProgressListener = Arc<dyn Fn(&str, &DownloadStatus) + Send + Sync>;
+
+/// Who asked for a download, requesters take turns when the queue is busy
+#[derive(Clone)]
+pub struct DownloadRequester {
+    pub user_id: u32,
+    pub listener: Option<ProgressListener>,
+}
+
+impl DownloadRequester {
+    fn notify(&self, id: &str, status: &DownloadStatus) {
+        if let Some(listener) = &self.listener {
+            listener(id, status);
+        }
+    }
+}
+
+struct Waiter {
+    requester: DownloadRequester,
+    tx: Sender<Result<Song, GettingSongError>>,
+}
+
+struct Job {
+    waiters: Vec<Waiter>,
+    running: bool,
+    /// queued by `enqueue_background` and not requested by a player since
+    background: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// queued and running downloads by video ID, a second request for an ID joins the job
+    jobs: HashMap<String, Job>,
+    /// video IDs waiting for a worker, per requester
+    queued: HashMap<u32, VecDeque<String>>,
+    /// requesters with queued downloads, in the order they get their next turn
+    turns: VecDeque<u32>,
+    /// video IDs downloaded only when no player is waiting for a download
+    background: VecDeque<String>,
+    background_running: usize,
+}
+
+impl Queue {
+    fn queued_count(&self) -> usize {
+        self.queued.values().map(VecDeque::len).sum()
+    }
+
+    /// Takes the next video ID, round robin between requesters,
+    /// background downloads only when no requester is waiting
+    fn next(&mut self) -> Option<String> {
+        let Some(user_id) = self.turns.pop_front() else {
+            if self.background_running >= BACKGROUND_WORKERS {
+                return None;
+            }
+            let id = self.background.pop_front()?;
+            self.background_running += 1;
+            return Some(id);
+        };
+        let user_queue = self.queued.get_mut(&user_id)?;
+        let id = user_queue.pop_front();
+        if user_queue.is_empty() {
+            self.queued.remove(&user_id);
+        } else {
+            self.turns.push_back(user_id);
+        }
+        id
+    }
+
+    fn push_for(&mut self, user_id: u32, id: &str) {
+        let user_queue = self.queued.entry(user_id).or_default();
+        user_queue.push_back(id.to_owned());
+        if user_queue.len() == 1 {
+            self.turns.push_back(user_id);
+        }
+    }
+}
+
+static QUEUE: Lazy<(Mutex<Queue>, Condvar)> =
+    Lazy::new(|| (Mutex::new(Queue::default()), Condvar::new()));
+
+/// Queues the download of a video, the song (or error) is sent on the returned channel.
+/// Requesting an ID that is already queued or downloading shares that download.
+pub fn enqueue(id: &str, requester: DownloadRequester) -> Receiver<Result<Song, GettingSongError>> {
+    let (tx, rx) = channel();
+    let (queue, available) = &*QUEUE;
+    let mut queue = queue.lock().unwrap();
+
+    let status = match queue.jobs.get_mut(id) {
+        Some(job) => {
+            let status = match job.running {
+                true => DownloadStatus::Downloading,
+                false => DownloadStatus::Queued(0),
+            };
+            job.waiters.push(Waiter {
+                requester: requester.clone(),
+                tx,
+            });
+            // a player wants it now, it moves from the background queue to theirs
+            if job.background && !job.running {
+                job.background = false;
+                queue.background.retain(|queued| queued != id);
+                queue.push_for(requester.user_id, id);
+                available.notify_one();
+            }
+            status
+        }
+        None => {
+            let ahead = queue.queued_count();
+            queue.jobs.insert(
+                id.to_owned(),
+                Job {
+                    waiters: vec![Waiter {
+                        requester: requester.clone(),
+                        tx,
+                    }],
+                    running: false,
+                    background: false,
+                },
+            );
+            queue.push_for(requester.user_id, id);
+            available.notify_one();
+            DownloadStatus::Queued(ahead)
+        }
+    };
+    drop(queue);
+
+    requester.notify(id, &status);
+    rx
+}
+
+/// Queues a download that only runs when no player is waiting for one.
+/// Does nothing if the ID is already queued or downloading.
+pub fn enqueue_background(id: &str) {
+    let (queue, available) = &*QUEUE;
+    let mut queue = queue.lock().unwrap();
+    if queue.jobs.contains_key(id) {
+        return;
+    }
+    queue.jobs.insert(
+        id.to_owned(),
+        Job {
+            waiters: vec![],
+            running: false,
+            background: true,
+        },
+    );
+    queue.background.push_back(id.to_owned());
+    available.notify_one();
+}
+
+/// Queues a download and waits for it
+pub fn download(id: &str, requester: DownloadRequester) -> Result<Song, GettingSongError> {
+    enqueue(id, requester)
+        .recv()
+        .unwrap_or(Err(GettingSongError::OtherError))
+}
+
+fn work() {
+    let (queue, available) = &*QUEUE;
+    loop {
+        let (id, requesters) = {
+            let mut queue = queue.lock().unwrap();
+            let id = loop {
+                match queue.next() {
+                    Some(id) => break id,
+                    None => queue = available.wait(queue).unwrap(),
+                }
+            };
+            let Some(job) = queue.jobs.get_mut(&id) else {
+                continue;
+            };
+            job.running = true;
+            let requesters = job
+                .waiters
+                .iter()
+                .map(|waiter| waiter.requester.clone())
+                .collect::<Vec<_>>();
+            (id, requesters)
+        };
+        requesters
+            .iter()
+            .for_each(|requester| requester.notify(&id, &DownloadStatus::Downloading));
+
+        // a panicking download still finishes its job, or its waiters would wait forever
+        let result = panic::catch_unwind(AssertUnwindSafe(|| download_song_from_id(&id)))
+            .unwrap_or_else(|_| {
+                eprintln!("[MUSIC] download of {} panicked", id);
+                Err(GettingSongError::OtherError)
+            });
+
+        let job = {
+            let mut queue = queue.lock().unwrap();
+            let job = queue.jobs.remove(&id);
+            if job.as_ref().is_some_and(|job| job.background) {
+                queue.background_running -= 1;
+                // the next background download can start
+                available.notify_one();
+            }
+            job
+        };
+        let Some(job) = job else {
+            continue;
+        };
+        let status = match &result {
+            Ok(_) => DownloadStatus::Done,
+            Err(err) => DownloadStatus::Failed(format!("{:?}", err)),
+        };
+        job.waiters.into_iter().for_each(|waiter| {
+            waiter.requester.notify(&id, &status);
+            let _ = waiter.tx.send(result.clone());
+        });
+    }
+}
+
+pub fn start_download_workers() {
+    println!("[MUSIC] starting {} download workers", WORKER_COUNT);
+    for _ in 0..WORKER_COUNT {
+        thread::spawn(work);
+    }
+}
@@ -0,0 +1,498 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use invidious::{ClientSync, ClientSyncTrait, CommonVideo, InvidiousError, MethodSync};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::{
+    config::config,
+    model::{
+        search_result::SearchResult,
+        song::{GettingSongError, Song},
+    },
+};
+
+use super::{
+    bounded_map,
+    source::{MusicSource, SearchResults},
+    yt_dlp,
+};
+
+// static API_CLIENT: Lazy<RwLock<invidious::ClientSync>> =
+//     Lazy::new(|| RwLock::new(invidious::ClientSync::default()));
+
+/// how many video metadata requests a playlist resolves at the same time
+const METADATA_WORKERS: usize = 4;
+
+/// consecutive failures after which an instance isn't used for a while
+const FAILURES_TO_EVICT: u32 = 3;
+/// how long a failing instance isn't used before it gets another chance
+const EVICTION: Duration = Duration::from_secs(5 * 60);
+/// how many instances a request is tried on before it fails
+const ATTEMPTS: usize = 3;
+/// wait before the first retry, doubled for every further one
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+static INSTANCE_FINDER: Lazy<InstanceFinder> =
+    Lazy::new(|| InstanceFinder::new(Vec::with_capacity(config().invidious.instance_count)));
+
+#[derive(Deserialize)]
+struct Skip {}
+
+/// How an instance answered the requests sent to it
+#[derive(Debug, Default, Clone)]
+struct Health {
+    /// moving average of the response time of successful requests
+    latency: Option<Duration>,
+    requests: u32,
+    failures: u32,
+    consecutive_failures: u32,
+    evicted_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_evicted(&self, now: Instant) -> bool {
+        self.evicted_until.is_some_and(|until| until > now)
+    }
+}
+
+#[derive(Debug)]
+pub struct InstanceFinder {
+    instances: RwLock<Vec<String>>,
+    rr_index: AtomicUsize,
+    /// by host, of the current and the backup instances
+    health: Mutex<HashMap<String, Health>>,
+}
+
+impl InstanceFinder {
+    fn new(instances: Vec<String>) -> Self {
+        Self {
+            instances: RwLock::new(instances),
+            rr_index: AtomicUsize::new(0),
+            health: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The next instance that isn't evicted, round robin. The backup instances are
+    /// only used when every instance is down, `None` when they are down too.
+    pub fn get_instance(&self) -> Option<String> {
+        self.get_instance_except(None)
+    }
+
+    /// Like `get_instance`, but never `skip`, to ask another instance the same thing
+    fn get_instance_except(&self, skip: Option<&str>) -> Option<String> {
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+        let is_up = |host: &String| {
+            skip != Some(host.as_str()) && !health.get(host).is_some_and(|h| h.is_evicted(now))
+        };
+
+        let instances = self.instances.read().unwrap();
+        let healthy = instances
+            .iter()
+            .filter(|host| is_up(host))
+            .collect::<Vec<_>>();
+        if !healthy.is_empty() {
+            let rr_idx = self.rr_index.fetch_add(1, SeqCst);
+            return Some(healthy[rr_idx % healthy.len()].clone());
+        }
+        let backups = InstanceFinder::backup_instances();
+        backups.iter().find(|host| is_up(host)).cloned()
+    }
+
+    /// Records how a request to an instance went, failing ones are evicted for a while
+    fn report(&self, host: &str, result: Result<Duration, &str>) {
+        let mut all_health = self.health.lock().unwrap();
+        let health = all_health.entry(host.to_owned()).or_default();
+        health.requests += 1;
+        match result {
+            Ok(elapsed) => {
+                health.consecutive_failures = 0;
+                health.evicted_until = None;
+                health.latency = Some(match health.latency {
+                    Some(latency) => (latency * 3 + elapsed) / 4,
+                    None => elapsed,
+                });
+            }
+            Err(reason) => {
+                health.failures += 1;
+                health.consecutive_failures += 1;
+                if health.consecutive_failures >= FAILURES_TO_EVICT {
+                    eprintln!(
+                        "[INVIDIOUS] not using {} for {:?}, {} of {} requests failed, last: {}",
+                        host, EVICTION, health.failures, health.requests, reason
+                    );
+                    health.evicted_until = Some(Instant::now() + EVICTION);
+                }
+            }
+        }
+    }
+
+    fn backup_instances() -> Vec<String> {
+        config().invidious.backup_instances.clone()
+    }
+
+    /// Select the healthiest instance from the list of instances and replace the current ones
+    fn update_instances(&self) {
+        let instance_count = config().invidious.instance_count;
+        let best_instances = match instances_from_api() {
+            Some(instances) => instances.into_iter().take(instance_count).collect(),
+            None => {
+                eprintln!("[UPDATER] couldn't get instances, using backup instances");
+                InstanceFinder::backup_instances()
+            }
+        };
+        println!("[UPDATER] using instances: {:?}", best_instances);
+        for (host, health) in self.health.lock().unwrap().iter() {
+            println!(
+                "[UPDATER] {}: {} of {} requests failed, latency {:?}",
+                host, health.failures, health.requests, health.latency
+            );
+        }
+        let mut instances = self.instances.write().unwrap();
+        instances.clear();
+        instances.extend(best_instances);
+    }
+}
+
+/// Whether an error says something about the instance rather than the request.
+/// API errors, like for a private video, don't, but they are retried once on another
+/// instance as they can be regional.
+fn is_instance_failure(err: &InvidiousError) -> bool {
+    matches!(
+        err,
+        InvidiousError::Fetch { .. } | InvidiousError::SerdeError { .. }
+    )
+}
+
+/// Runs a request on the next healthy instance, see `retry_on_instances`
+fn with_instance<T>(
+    request: impl Fn(&ClientSync) -> Result<T, InvidiousError>,
+) -> Result<T, GettingSongError> {
+    retry_on_instances(&INSTANCE_FINDER, |host| {
+        request(&ClientSync::with_method(
+            format!("https://{}", host),
+            MethodSync::Isahc,
+        ))
+    })
+}
+
+/// Runs a request on the next healthy instance of `finder`. Transport and instance
+/// failures are retried on the following instances, waiting longer before every retry.
+/// API errors are answers of a working instance, like an unavailable video, and are
+/// tried once more on a different instance since they can be regional.
+/// Other errors are returned right away.
+fn retry_on_instances<T>(
+    finder: &InstanceFinder,
+    request: impl Fn(&str) -> Result<T, InvidiousError>,
+) -> Result<T, GettingSongError> {
+    let mut last_err = GettingSongError::Unavailable;
+    // the instance that answered with an API error, the next attempt goes elsewhere
+    let mut api_error_host: Option<String> = None;
+    let mut api_error_retried = false;
+    for attempt in 0..ATTEMPTS {
+        let host = match api_error_host.take() {
+            Some(answered) => finder.get_instance_except(Some(&answered)),
+            None => {
+                if attempt > 0 {
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt as u32 - 1));
+                }
+                finder.get_instance()
+            }
+        };
+        let Some(host) = host else {
+            break;
+        };
+        let started = Instant::now();
+        match request(&host) {
+            Ok(value) => {
+                finder.report(&host, Ok(started.elapsed()));
+                return Ok(value);
+            }
+            Err(err @ InvidiousError::ApiError { .. }) if !api_error_retried => {
+                finder.report(&host, Ok(started.elapsed()));
+                eprintln!(
+                    "[INVIDIOUS] {} answered {}, asking another instance",
+                    host, err
+                );
+                api_error_retried = true;
+                api_error_host = Some(host);
+                last_err = GettingSongError::from(err);
+            }
+            Err(err) if !is_instance_failure(&err) => {
+                finder.report(&host, Ok(started.elapsed()));
+                return Err(GettingSongError::from(err));
+            }
+            Err(err) => {
+                let reason = err.to_string();
+                finder.report(&host, Err(&reason));
+                eprintln!(
+                    "[INVIDIOUS] attempt {} of {} on {} failed: {}",
+                    attempt + 1,
+                    ATTEMPTS,
+                    host,
+                    reason
+                );
+                last_err = GettingSongError::from(err);
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// All instances the instances API knows, healthiest first.
+/// `None` when no API is configured or it can't be reached.
+fn instances_from_api() -> Option<Vec<String>> {
+    let api = &config().invidious.instances_api;
+    if api.is_empty() {
+        return None;
+    }
+    match reqwest::blocking::get(api).and_then(|res| res.json::<Vec<(String, Skip)>>()) {
+        Ok(instances) => Some(instances.into_iter().map(|(uri, _)| uri).collect()),
+        Err(err) => {
+            eprintln!("[UPDATER] instances API failed: {}", err);
+            None
+        }
+    }
+}
+
+/// Response times of the instances the API suggests and the backup instances,
+/// fastest first, with the unreachable ones and why at the end
+pub fn probe_instances() -> Vec<(String, Result<Duration, String>)> {
+    const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+    const PROBE_WORKERS: usize = 8;
+
+    let options = &config().invidious;
+    let mut candidates = instances_from_api()
+        .unwrap_or_default()
+        .into_iter()
+        .take(options.instance_count * 4)
+        .collect::<Vec<_>>();
+    for backup in &options.backup_instances {
+        if !candidates.contains(backup) {
+            candidates.push(backup.clone());
+        }
+    }
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .expect("default TLS backend to be available");
+    let mut results = bounded_map(&candidates, PROBE_WORKERS, |instance| {
+        let started = Instant::now();
+        let result = client
+            .get(format!("https://{}/api/v1/stats", instance))
+            .send()
+            .and_then(|res| res.error_for_status())
+            .map(|_| started.elapsed())
+            .map_err(|err| err.to_string());
+        (instance.clone(), result)
+    });
+    results.sort_by_key(|(_, result)| match result {
+        Ok(elapsed) => (false, *elapsed),
+        Err(_) => (true, Duration::ZERO),
+    });
+    results
+}
+
+/// multithreaded playlist resolver
+fn common_vids_from_id(id: &str) -> Result<Vec<CommonVideo>, GettingSongError> {
+    if id.starts_with("UC") {
+        // API errors are asked once more on another instance, needed when region blocked
+        let channel_vids =
+            with_instance(|client| client.channel_videos(id, Some("sort_by=popular")))?;
+        return Ok(channel_vids.videos);
+    }
+    if !id.starts_with("PL") {
+        return Ok(vec![]);
+    };
+    let playlist = with_instance(|client| client.playlist(id, None))?;
+    let results = bounded_map(&playlist.videos, METADATA_WORKERS, |playlist_item| {
+        with_instance(|client| client.video(&playlist_item.id, None)).map(CommonVideo::from)
+    });
+    // unavailable videos are left out, the playlist only fails if none could be resolved
+    let mut last_err = None;
+    let videos = results
+        .into_iter()
+        .filter_map(|result| result.map_err(|err| last_err = Some(err)).ok())
+        .collect::<Vec<_>>();
+    if let Some(err) = last_err {
+        eprintln!(
+            "[INVIDIOUS] skipped {} of {} videos of {}, last error: {:?}",
+            playlist.videos.len() - videos.len(),
+            playlist.videos.len(),
+            id,
+            err
+        );
+        if videos.is_empty() {
+            return Err(err);
+        }
+    }
+    Ok(videos)
+}
+
+/// Songs from YouTube, found through Invidious and downloaded with yt-dlp
+pub struct InvidiousSource;
+
+impl MusicSource for InvidiousSource {
+    fn name(&self) -> &'static str {
+        "invidious"
+    }
+
+    fn start(&self) {
+        INSTANCE_FINDER.update_instances();
+        println!("[MUSIC] starting instance updater");
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_secs(60 * 480)); // 8 hours
+            INSTANCE_FINDER.update_instances();
+        });
+    }
+
+    /// YouTube IDs never contain a colon, other sources prefix their IDs with `<name>:`
+    fn owns_id(&self, id: &str) -> bool {
+        !id.contains(':')
+    }
+
+    fn search(&self, query: &str) -> Result<SearchResults, GettingSongError> {
+        let search_items = with_instance(|client| {
+            client.search(Some(format!("q={}", query.replace(' ', "+")).as_str()))
+        })?
+        .items
+        .into_iter()
+        .take(6)
+        .collect::<Vec<_>>();
+
+        let songs = search_items
+            .iter()
+            .filter_map(|item| match item {
+                invidious::hidden::SearchItem::Video(vd) => Some(Song::from(vd)),
+                _ => None, // channel & playlist vids would need another request
+            })
+            .collect();
+        Ok(SearchResults {
+            items: search_items.into_iter().map(SearchResult::from).collect(),
+            songs,
+        })
+    }
+
+    fn resolve(&self, id: &str) -> Result<Song, GettingSongError> {
+        Ok(Song::from(&CommonVideo::from(with_instance(|client| {
+            client.video(id, None)
+        })?)))
+    }
+
+    fn expand(&self, id: &str) -> Result<Option<Vec<Song>>, GettingSongError> {
+        match id.get(..2) {
+            Some("UC" | "PL") => Ok(Some(
+                common_vids_from_id(id)?.iter().map(Song::from).collect(),
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    fn fetch_audio(&self, id: &str, songs_dir: &Path) -> Result<(), GettingSongError> {
+        yt_dlp::download(songs_dir, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(finder: &InstanceFinder, host: &str) {
+        for _ in 0..FAILURES_TO_EVICT {
+            finder.report(host, Err("timed out"));
+        }
+    }
+
+    #[test]
+    fn failing_instances_are_skipped_until_all_are_down() {
+        let finder = InstanceFinder::new(vec!["a".to_string(), "b".to_string()]);
+        fail(&finder, "a");
+        assert!((0..4).all(|_| finder.get_instance().as_deref() == Some("b")));
+
+        // a success clears the failures
+        finder.report("a", Ok(Duration::from_millis(80)));
+        assert!((0..4).any(|_| finder.get_instance().as_deref() == Some("a")));
+
+        fail(&finder, "a");
+        fail(&finder, "b");
+        let backups = InstanceFinder::backup_instances();
+        assert_eq!(finder.get_instance().as_ref(), backups.first());
+        backups.iter().for_each(|backup| fail(&finder, backup));
+        assert_eq!(finder.get_instance(), None);
+    }
+
+    #[test]
+    fn only_transport_errors_count_against_instances() {
+        let unavailable = InvidiousError::ApiError {
+            message: "This video is unavailable".to_string(),
+        };
+        let unreachable = InvidiousError::Fetch {
+            error: "connection refused".into(),
+        };
+        assert!(!is_instance_failure(&unavailable));
+        assert!(is_instance_failure(&unreachable));
+    }
+
+    #[test]
+    fn only_instance_failures_are_retried() {
+        let finder = InstanceFinder::new(vec!["a".to_string(), "b".to_string()]);
+        let hosts = Mutex::new(vec![]);
+        let unavailable = retry_on_instances(&finder, |host| -> Result<(), _> {
+            hosts.lock().unwrap().push(host.to_string());
+            Err(InvidiousError::ApiError {
+                message: "This video is unavailable".to_string(),
+            })
+        });
+        assert!(unavailable.is_err());
+        // asked once more elsewhere, in case the video is region blocked
+        let asked = std::mem::take(&mut *hosts.lock().unwrap());
+        assert_eq!(asked.len(), 2);
+        assert_ne!(asked[0], asked[1]);
+        // neither instance counts as failing
+        assert!((0..4).any(|_| finder.get_instance().as_deref() == Some("a")));
+        assert!((0..4).any(|_| finder.get_instance().as_deref() == Some("b")));
+
+        let calls = AtomicUsize::new(0);
+        let invalid = retry_on_instances(&finder, |_| -> Result<(), _> {
+            calls.fetch_add(1, SeqCst);
+            Err(InvidiousError::Message {
+                message: "invalid request".to_string(),
+            })
+        });
+        assert!(invalid.is_err());
+        assert_eq!(calls.into_inner(), 1);
+
+        let hosts = Mutex::new(vec![]);
+        let unreachable = retry_on_instances(&finder, |host| -> Result<(), _> {
+            hosts.lock().unwrap().push(host.to_string());
+            Err(InvidiousError::Fetch {
+                error: "connection refused".into(),
+            })
+        });
+        assert!(unreachable.is_err());
+        let hosts = hosts.into_inner().unwrap();
+        assert_eq!(hosts.len(), ATTEMPTS);
+        // the instance that failed isn't asked again right away
+        assert_ne!(hosts[0], hosts[1]);
+    }
+
+    #[test]
+    fn empty_instance_list_uses_the_backups() {
+        let finder = InstanceFinder::new(vec![]);
+        assert_eq!(
+            finder.get_instance().as_ref(),
+            InstanceFinder::backup_instances().first()
+        );
+    }
+}
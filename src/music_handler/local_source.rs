@@ -0,0 +1,447 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use once_cell::sync::Lazy;
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::Hint,
+};
+
+use crate::{
+    config::config,
+    model::{
+        search_result::SearchResult,
+        song::{GettingSongError, Song},
+    },
+};
+
+use super::source::{MusicSource, SearchResults};
+
+const ID_PREFIX: &str = "local:";
+const ALBUM_ID_PREFIX: &str = "local:album:";
+const AUDIO_EXTENSIONS: [&str; 9] = [
+    "mp3", "flac", "ogg", "opus", "oga", "m4a", "mp4", "wav", "webm",
+];
+const MAX_RESULTS: usize = 6;
+
+struct LocalTrack {
+    path: PathBuf,
+    song: Song,
+}
+
+#[derive(Default)]
+struct LibraryIndex {
+    tracks: BTreeMap<String, LocalTrack>,
+    /// album ID -> (album name, track IDs)
+    albums: BTreeMap<String, (String, Vec<String>)>,
+}
+
+static INDEX: Lazy<RwLock<LibraryIndex>> = Lazy::new(|| RwLock::new(LibraryIndex::default()));
+
+/// FNV-1a, stable across builds so IDs survive restarts
+fn stable_hash(value: &str) -> String {
+    let hash = value.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+fn audio_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            audio_files(&path, files);
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    year: Option<i32>,
+}
+
+impl Tags {
+    fn read(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            // RIFF INFO values are NUL terminated
+            let value = tag
+                .value
+                .to_string()
+                .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::AlbumArtist) => {
+                    self.artist.get_or_insert(value);
+                }
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                Some(
+                    StandardTagKey::Date
+                    | StandardTagKey::ReleaseDate
+                    | StandardTagKey::OriginalDate,
+                ) => {
+                    if let Some(year) = value.get(..4).and_then(|year| year.parse().ok()) {
+                        self.year.get_or_insert(year);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Reads title, artist, album, year and length from the file's ID3/Vorbis/MP4 tags
+fn read_track(path: &Path) -> Option<(Tags, u32)> {
+    let file = File::open(path).ok()?;
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            MediaSourceStream::new(Box::new(file), Default::default()),
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let mut tags = Tags::default();
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            tags.read(revision);
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.read(revision);
+    }
+
+    let length = probed
+        .format
+        .default_track()
+        .and_then(|track| {
+            let params = &track.codec_params;
+            Some(params.n_frames? / params.sample_rate? as u64)
+        })
+        .unwrap_or(0);
+    Some((tags, length as u32))
+}
+
+fn index_library(dir: &Path) -> LibraryIndex {
+    let mut files = vec![];
+    audio_files(dir, &mut files);
+    // albums list their tracks in file name order, not in the order of the file system
+    files.sort();
+
+    let mut index = LibraryIndex::default();
+    for path in files {
+        let Some((tags, length)) = read_track(&path) else {
+            eprintln!("[LOCAL] couldn't read {:?}", path);
+            continue;
+        };
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let id = format!("{}{}", ID_PREFIX, stable_hash(&relative.to_string_lossy()));
+        let artist = tags.artist.unwrap_or_else(|| "Unknown Artist".to_string());
+        let song = Song {
+            id: id.clone(),
+            title: tags.title.unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            }),
+            artist: artist.clone(),
+            length,
+            published: 0,
+            album: tags.album.clone(),
+            release_year: tags.year,
+        };
+        if let Some(album) = tags.album {
+            let album_id = format!(
+                "{}{}",
+                ALBUM_ID_PREFIX,
+                stable_hash(&format!("{}\n{}", artist, album))
+            );
+            index
+                .albums
+                .entry(album_id)
+                .or_insert_with(|| (album, vec![]))
+                .1
+                .push(id.clone());
+        }
+        index.tracks.insert(id, LocalTrack { path, song });
+    }
+    index
+}
+
+fn matches_query(query: &[String], fields: &[&str]) -> bool {
+    let haystack = fields.join(" ").to_lowercase();
+    query.iter().all(|word| haystack.contains(word))
+}
+
+/// Songs from a local directory of audio files, for games without internet
+pub struct LocalSource;
+
+impl MusicSource for LocalSource {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn start(&self) {
+        let dir = &config().sources.local_library;
+        println!("[LOCAL] indexing library {:?}", dir);
+        let index = index_library(dir);
+        println!(
+            "[LOCAL] indexed {} tracks in {} albums",
+            index.tracks.len(),
+            index.albums.len()
+        );
+        *INDEX.write().unwrap() = index;
+    }
+
+    fn owns_id(&self, id: &str) -> bool {
+        id.starts_with(ID_PREFIX)
+    }
+
+    fn search(&self, query: &str) -> Result<SearchResults, GettingSongError> {
+        let query = query
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        let index = INDEX.read().unwrap();
+
+        let songs = index
+            .tracks
+            .values()
+            .map(|track| &track.song)
+            .filter(|song| {
+                matches_query(
+                    &query,
+                    &[
+                        &song.title,
+                        &song.artist,
+                        song.album.as_deref().unwrap_or(""),
+                    ],
+                )
+            })
+            .take(MAX_RESULTS)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut items = songs
+            .iter()
+            .map(|song| SearchResult {
+                name: format!("{} - {}", song.artist, song.title),
+                id: song.id.clone(),
+                r#type: "video".to_string(),
+            })
+            .collect::<Vec<_>>();
+        items.extend(
+            index
+                .albums
+                .iter()
+                .filter(|(_, (album, _))| matches_query(&query, &[album]))
+                .take(MAX_RESULTS)
+                .map(|(album_id, (album, _))| SearchResult {
+                    name: album.clone(),
+                    id: album_id.clone(),
+                    r#type: "playlist".to_string(),
+                }),
+        );
+        Ok(SearchResults { items, songs })
+    }
+
+    fn resolve(&self, id: &str) -> Result<Song, GettingSongError> {
+        INDEX
+            .read()
+            .unwrap()
+            .tracks
+            .get(id)
+            .map(|track| track.song.clone())
+            .ok_or(GettingSongError::Unavailable)
+    }
+
+    fn expand(&self, id: &str) -> Result<Option<Vec<Song>>, GettingSongError> {
+        if !id.starts_with(ALBUM_ID_PREFIX) {
+            return Ok(None);
+        }
+        let index = INDEX.read().unwrap();
+        let (_, track_ids) = index.albums.get(id).ok_or(GettingSongError::Unavailable)?;
+        Ok(Some(
+            track_ids
+                .iter()
+                .filter_map(|track_id| index.tracks.get(track_id))
+                .map(|track| track.song.clone())
+                .collect(),
+        ))
+    }
+
+    /// links (or copies) the file into the songs directory, so it's served like any download
+    fn fetch_audio(&self, id: &str, songs_dir: &Path) -> Result<(), GettingSongError> {
+        let path = INDEX
+            .read()
+            .unwrap()
+            .tracks
+            .get(id)
+            .map(|track| track.path.clone())
+            .ok_or(GettingSongError::Unavailable)?;
+        let dest = songs_dir.join(id);
+        if fs::hard_link(&path, &dest).is_err() {
+            fs::copy(&path, &dest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A second of silence with RIFF INFO tags like `INAM` (title) or `IPRD` (album)
+    fn tagged_wav(tags: &[(&[u8; 4], &str)]) -> Vec<u8> {
+        let mut info = b"INFO".to_vec();
+        for (key, value) in tags {
+            let mut value = value.as_bytes().to_vec();
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            info.extend_from_slice(*key);
+            info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            info.extend_from_slice(&value);
+        }
+        let samples = vec![0_u8; 8000 * 2];
+
+        let mut chunks = b"WAVEfmt ".to_vec();
+        chunks.extend_from_slice(&16_u32.to_le_bytes());
+        chunks.extend_from_slice(&1_u16.to_le_bytes()); // PCM
+        chunks.extend_from_slice(&1_u16.to_le_bytes()); // channels
+        chunks.extend_from_slice(&8000_u32.to_le_bytes());
+        chunks.extend_from_slice(&16000_u32.to_le_bytes()); // byte rate
+        chunks.extend_from_slice(&2_u16.to_le_bytes()); // block align
+        chunks.extend_from_slice(&16_u16.to_le_bytes()); // bits per sample
+        chunks.extend_from_slice(b"LIST");
+        chunks.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        chunks.extend_from_slice(&info);
+        chunks.extend_from_slice(b"data");
+        chunks.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        chunks.extend_from_slice(&samples);
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&chunks);
+        wav
+    }
+
+    /// A library with an album of two tagged tracks, an untagged track and a file that isn't audio
+    fn library(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gts-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("Artist A")).unwrap();
+        let album = |title| {
+            tagged_wav(&[
+                (b"INAM", title),
+                (b"IART", "Artist A"),
+                (b"IPRD", "Album X"),
+                (b"ICRD", "1999-05-01"),
+            ])
+        };
+        fs::write(dir.join("Artist A/one.wav"), album("First Song")).unwrap();
+        fs::write(dir.join("Artist A/two.wav"), album("Second Song")).unwrap();
+        fs::write(dir.join("untagged.wav"), tagged_wav(&[])).unwrap();
+        fs::write(dir.join("notes.txt"), "not audio").unwrap();
+        dir
+    }
+
+    #[test]
+    fn ids_are_stable_fnv_hashes_of_the_relative_path() {
+        assert_eq!(stable_hash(""), "cbf29ce484222325");
+        assert_eq!(stable_hash("a"), "af63dc4c8601ec8c");
+
+        let dir = library("local-ids");
+        let first = index_library(&dir);
+        let second = index_library(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.tracks.len(), 3);
+        assert!(first.tracks.keys().eq(second.tracks.keys()));
+        let untagged_id = format!("{}{}", ID_PREFIX, stable_hash("untagged.wav"));
+        assert!(first.tracks.contains_key(&untagged_id));
+    }
+
+    #[test]
+    fn tags_fall_back_to_the_file_name() {
+        let dir = library("local-tags");
+        let index = index_library(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let song = |file: &str| &index.tracks[&format!("{}{}", ID_PREFIX, stable_hash(file))].song;
+        let tagged = song("Artist A/one.wav");
+        assert_eq!(tagged.title, "First Song");
+        assert_eq!(tagged.artist, "Artist A");
+        assert_eq!(tagged.album.as_deref(), Some("Album X"));
+        assert_eq!(tagged.year(), Some(1999));
+        assert_eq!(tagged.length, 1);
+
+        let untagged = song("untagged.wav");
+        assert_eq!(untagged.title, "untagged");
+        assert_eq!(untagged.artist, "Unknown Artist");
+        assert_eq!(untagged.album, None);
+        assert_eq!(untagged.year(), None);
+    }
+
+    #[test]
+    fn albums_expand_and_tracks_are_linked_or_copied() {
+        let dir = library("local-albums");
+        let songs_dir = dir.join("songs");
+        fs::create_dir_all(&songs_dir).unwrap();
+        *INDEX.write().unwrap() = index_library(&dir);
+
+        let album_id = format!("{}{}", ALBUM_ID_PREFIX, stable_hash("Artist A\nAlbum X"));
+        let found = LocalSource.search("album x").unwrap();
+        assert!(found.items.iter().any(|item| item.id == album_id));
+        let titles = LocalSource
+            .expand(&album_id)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|song| song.title)
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["First Song", "Second Song"]);
+        let track_id = format!("{}{}", ID_PREFIX, stable_hash("Artist A/one.wav"));
+        assert!(LocalSource.expand(&track_id).unwrap().is_none());
+        assert!(LocalSource.expand("local:album:missing").is_err());
+
+        let original = fs::read(dir.join("Artist A/one.wav")).unwrap();
+        LocalSource.fetch_audio(&track_id, &songs_dir).unwrap();
+        let linked = fs::read(songs_dir.join(&track_id)).unwrap();
+        // a leftover file makes the hard link fail, the track is copied over it
+        let untagged_id = format!("{}{}", ID_PREFIX, stable_hash("untagged.wav"));
+        fs::write(songs_dir.join(&untagged_id), b"leftover").unwrap();
+        LocalSource.fetch_audio(&untagged_id, &songs_dir).unwrap();
+        let copied = fs::read(songs_dir.join(&untagged_id)).unwrap();
+        let untagged = fs::read(dir.join("untagged.wav")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(linked, original);
+        assert_eq!(copied, untagged);
+    }
+}
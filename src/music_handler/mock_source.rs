@@ -0,0 +1,160 @@
+use std::{fs, path::Path};
+
+use crate::model::{
+    search_result::SearchResult,
+    song::{GettingSongError, Song},
+};
+
+use super::source::{MusicSource, SearchResults};
+
+const ID_PREFIX: &str = "mock:";
+/// collection with every canned song
+pub const PLAYLIST_ID: &str = "mock:playlist";
+/// length of the generated audio
+const SONG_SECS: u32 = 2;
+const SAMPLE_RATE: u32 = 8000;
+
+/// title, artist, release year
+const SONGS: [(&str, &str, i32); 8] = [
+    ("Morning Tide", "The Placeholders", 1975),
+    ("Static Hearts", "The Placeholders", 1979),
+    ("Neon Avenue", "Synthetic Dreams", 1984),
+    ("Paper Planes Tonight", "Synthetic Dreams", 1987),
+    ("Glass Garden", "Velvet Echo", 1993),
+    ("Slow Satellite", "Velvet Echo", 1998),
+    ("Golden Hour", "Lumen", 2006),
+    ("Last Train Home", "Lumen", 2014),
+];
+
+fn song(idx: usize) -> Song {
+    let (title, artist, year) = SONGS[idx];
+    Song {
+        id: format!("{}{}", ID_PREFIX, idx),
+        title: title.to_string(),
+        artist: artist.to_string(),
+        length: SONG_SECS,
+        published: 0,
+        album: None,
+        release_year: Some(year),
+    }
+}
+
+fn song_idx(id: &str) -> Result<usize, GettingSongError> {
+    id.strip_prefix(ID_PREFIX)
+        .and_then(|idx| idx.parse::<usize>().ok())
+        .filter(|idx| *idx < SONGS.len())
+        .ok_or(GettingSongError::Unavailable)
+}
+
+/// A mono 16 bit WAV file with a sine tone, every song gets its own pitch
+fn sine_wav(idx: usize) -> Vec<u8> {
+    let frequency = 220.0 * (1.0 + idx as f32 / 4.0);
+    let sample_count = SONG_SECS * SAMPLE_RATE;
+    let data_len = sample_count * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes()); // fmt chunk size
+    wav.extend_from_slice(&1_u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1_u16.to_le_bytes()); // channels
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2_u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16_u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for n in 0..sample_count {
+        let t = n as f32 / SAMPLE_RATE as f32;
+        let sample =
+            (f32::sin(t * frequency * std::f32::consts::TAU) * i16::MAX as f32 / 4.0) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+/// Canned songs with generated audio, for tests that shouldn't touch the network
+pub struct MockSource;
+
+impl MusicSource for MockSource {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn owns_id(&self, id: &str) -> bool {
+        id.starts_with(ID_PREFIX)
+    }
+
+    /// every song whose title or artist contains the query, plus the playlist
+    fn search(&self, query: &str) -> Result<SearchResults, GettingSongError> {
+        let query = query.to_lowercase();
+        let songs = (0..SONGS.len())
+            .map(song)
+            .filter(|song| {
+                song.title.to_lowercase().contains(&query)
+                    || song.artist.to_lowercase().contains(&query)
+            })
+            .collect::<Vec<_>>();
+        let mut items = songs
+            .iter()
+            .map(|song| SearchResult {
+                name: song.title.clone(),
+                id: song.id.clone(),
+                r#type: "video".to_string(),
+            })
+            .collect::<Vec<_>>();
+        items.push(SearchResult {
+            name: "Mock Playlist".to_string(),
+            id: PLAYLIST_ID.to_string(),
+            r#type: "playlist".to_string(),
+        });
+        Ok(SearchResults { items, songs })
+    }
+
+    fn resolve(&self, id: &str) -> Result<Song, GettingSongError> {
+        song_idx(id).map(song)
+    }
+
+    fn expand(&self, id: &str) -> Result<Option<Vec<Song>>, GettingSongError> {
+        Ok((id == PLAYLIST_ID).then(|| (0..SONGS.len()).map(song).collect()))
+    }
+
+    fn fetch_audio(&self, id: &str, songs_dir: &Path) -> Result<(), GettingSongError> {
+        let idx = song_idx(id)?;
+        fs::write(songs_dir.join(id), sine_wav(idx))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_audio_is_a_valid_wav() {
+        let wav = sine_wav(3);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(wav.len(), 44 + (SONG_SECS * SAMPLE_RATE * 2) as usize);
+    }
+
+    #[test]
+    fn search_matches_title_and_artist() {
+        let found = MockSource.search("lumen").unwrap();
+        assert_eq!(found.songs.len(), 2);
+        assert!(found.items.iter().any(|item| item.id == PLAYLIST_ID));
+
+        let found = MockSource.search("glass").unwrap();
+        assert_eq!(found.songs[0].title, "Glass Garden");
+    }
+
+    #[test]
+    fn playlist_expands_to_every_song() {
+        let songs = MockSource.expand(PLAYLIST_ID).unwrap().unwrap();
+        assert_eq!(songs.len(), SONGS.len());
+        assert!(MockSource.expand("mock:0").unwrap().is_none());
+        assert_eq!(MockSource.resolve("mock:7").unwrap().year(), Some(2014));
+        assert!(MockSource.resolve("mock:8").is_err());
+    }
+}
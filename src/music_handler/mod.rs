@@ -0,0 +1,348 @@
+pub mod download_queue;
+mod invidious_source;
+mod local_source;
+#[cfg(any(test, feature = "mock-source"))]
+pub mod mock_source;
+pub mod prefetch;
+mod query_cache;
+pub mod song_cache;
+pub mod source;
+mod yt_dlp;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Mutex, RwLock,
+    },
+    thread,
+};
+
+use once_cell::sync::Lazy;
+use rand::{seq::SliceRandom, Rng};
+
+pub use self::invidious_source::probe_instances;
+use self::{
+    download_queue::DownloadRequester, invidious_source::InvidiousSource,
+    local_source::LocalSource, query_cache::QueryCache, source::MusicSource,
+};
+use crate::{
+    audio,
+    config::config,
+    game::snippets,
+    model::{
+        search_result::SearchResult,
+        song::{GettingSongError, Song},
+    },
+};
+
+/// cut the snippets that don't depend on chance right after processing a download
+const PRE_CUT_SNIPPETS: bool = true;
+
+fn source_by_name(name: &str) -> Option<Box<dyn MusicSource>> {
+    match name {
+        "invidious" => Some(Box::new(InvidiousSource)),
+        "local" => Some(Box::new(LocalSource)),
+        #[cfg(any(test, feature = "mock-source"))]
+        "mock" => Some(Box::new(mock_source::MockSource)),
+        _ => None,
+    }
+}
+
+pub fn is_known_source(name: &str) -> bool {
+    source_by_name(name).is_some()
+}
+
+/// the sources enabled in the config, the names were checked when it was loaded
+static SOURCES: Lazy<Vec<Box<dyn MusicSource>>> = Lazy::new(|| {
+    config()
+        .sources
+        .enabled
+        .iter()
+        .filter_map(|name| source_by_name(name))
+        .collect()
+});
+
+pub fn start_sources() {
+    let size = song_cache::size();
+    println!(
+        "[MUSIC] {} cached songs, {} MiB",
+        size.songs,
+        size.bytes / 1024 / 1024
+    );
+    let cached = song_cache::load_metadata(&songs_dir());
+    println!("[MUSIC] loaded metadata of {} cached songs", cached.len());
+    cache_metadata(&cached);
+    SOURCES.iter().for_each(|source| {
+        println!("[MUSIC] using source {}", source.name());
+        source.start();
+    });
+}
+
+fn source_for_id(id: &str) -> Result<&'static dyn MusicSource, GettingSongError> {
+    SOURCES
+        .iter()
+        .find(|source| source.owns_id(id))
+        .map(|source| source.as_ref())
+        .ok_or(GettingSongError::Unavailable)
+}
+
+/// Runs `f` for every item on at most `workers` threads, keeping the order of the items
+fn bounded_map<T: Sync, R: Send>(
+    items: &[T],
+    workers: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next_idx = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
+    thread::scope(|scope| {
+        for _ in 0..workers.min(items.len()) {
+            scope.spawn(|| loop {
+                let idx = next_idx.fetch_add(1, SeqCst);
+                let Some(item) = items.get(idx) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+static QUERY_CACHE: Lazy<RwLock<QueryCache>> = Lazy::new(|| {
+    let search = &config().search;
+    RwLock::new(QueryCache::new(search.cache_size, search.cache_ttl))
+});
+static ID_METADATA_CACHE: Lazy<RwLock<BTreeMap<String, Song>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// Searches every source, results of sources that fail are left out
+pub fn get_suggestions(query: &str) -> Result<Vec<SearchResult>, GettingSongError> {
+    let query = query_cache::normalize(query);
+    if let Some(items) = QUERY_CACHE.write().unwrap().get(&query) {
+        return Ok(items);
+    }
+
+    let mut results = vec![];
+    let mut last_err = None;
+    for source in SOURCES.iter() {
+        match source.search(&query) {
+            Ok(found) => {
+                cache_metadata(&found.songs);
+                results.extend(found.items);
+            }
+            Err(err) => {
+                eprintln!("[MUSIC] {} search failed: {:?}", source.name(), err);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    if results.is_empty() {
+        return match last_err {
+            Some(err) => Err(err),
+            None => Ok(vec![]),
+        };
+    }
+
+    let mut query_cache = QUERY_CACHE.write().unwrap();
+    query_cache.insert(query, results.clone());
+    let stats = query_cache.stats();
+    println!(
+        "[MUSIC] query cache: {} entries, {} hits, {} misses",
+        stats.entries, stats.hits, stats.misses
+    );
+
+    Ok(results)
+}
+
+fn cache_metadata(songs: &[Song]) {
+    let mut write_id_cache = ID_METADATA_CACHE.write().unwrap();
+    songs.iter().for_each(|song| {
+        write_id_cache.insert(song.id.clone(), song.clone());
+    });
+}
+
+/// Songs with cached metadata that are related to `song`:
+/// same artist first, then songs that showed up in the same search results
+pub fn related_songs(song: &Song) -> Vec<Song> {
+    let id_cache = ID_METADATA_CACHE.read().unwrap();
+    let mut related: Vec<Song> = id_cache
+        .values()
+        .filter(|cached| cached.id != song.id && cached.artist == song.artist)
+        .cloned()
+        .collect();
+
+    let query_cache = QUERY_CACHE.read().unwrap();
+    query_cache
+        .values()
+        .filter(|items| items.iter().any(|item| item.id == song.id))
+        .flatten()
+        .for_each(|item| {
+            if let Some(cached) = id_cache.get(&item.id) {
+                if cached.id != song.id && !related.iter().any(|s| s.id == cached.id) {
+                    related.push(cached.clone());
+                }
+            }
+        });
+    related
+}
+
+/// All songs with cached metadata
+pub fn known_songs() -> Vec<Song> {
+    ID_METADATA_CACHE
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Downloads a random selection of the top songs of a collection
+fn songs_from_collection(
+    collection: Vec<Song>,
+    requester: &DownloadRequester,
+) -> Result<Vec<Song>, GettingSongError> {
+    let mut songs = vec![];
+
+    let song_count = std::cmp::min(collection.len(), 5);
+    let mut top30: Vec<Song> = collection.into_iter().take(30).collect();
+    let mut initial_vids = vec![];
+    for _ in 0..song_count {
+        initial_vids.push(top30.swap_remove(rand::thread_rng().gen_range(0..top30.len())));
+    }
+
+    let downloads = initial_vids
+        .iter()
+        .map(|vid| download_queue::enqueue(&vid.id, requester.clone()))
+        .collect::<Vec<_>>();
+    for download in downloads {
+        if let Ok(Ok(song)) = download.recv() {
+            songs.push(song);
+        }
+    }
+
+    let mut attempts = 0;
+    while songs.len() < song_count && attempts < 5 {
+        let Some(vid) = top30.choose(&mut rand::thread_rng()) else {
+            break;
+        };
+        if !songs.iter().any(|s| s.id == vid.id) {
+            if let Ok(song) = download_queue::download(&vid.id, requester.clone()) {
+                songs.push(song);
+            }
+        }
+        attempts += 1;
+    }
+
+    Ok(songs)
+}
+
+/// Directory the downloaded songs are stored in and served from
+pub fn songs_dir() -> PathBuf {
+    std::env::current_dir()
+        .unwrap()
+        .join(&config().server.songs_dir)
+}
+
+/// Downloads and processes a song, only ever called by the download queue,
+/// which runs a single download per ID and hands its result to every waiter
+fn download_song_from_id(id: &str) -> Result<Song, GettingSongError> {
+    let songs_dir = songs_dir();
+    // several workers can get here at once, only one of them creates it
+    fs::create_dir_all(&songs_dir)?;
+
+    let source = source_for_id(id)?;
+    let song = {
+        let read_metadata = ID_METADATA_CACHE.read().unwrap();
+        if let Some(metadata) = read_metadata.get(id) {
+            metadata.clone()
+        } else {
+            drop(read_metadata);
+            let song = source.resolve(id)?;
+            let mut write_metadata = ID_METADATA_CACHE.write().unwrap();
+            write_metadata.insert(id.to_owned(), song.clone());
+            song
+        }
+    };
+
+    // the index notices files deleted by other processes, like the cache commands, only
+    // on the next sweep
+    if song_cache::contains(id) && !songs_dir.join(id).exists() {
+        song_cache::forget(id);
+    }
+    if !song_cache::contains(id) {
+        source.fetch_audio(id, &songs_dir)?;
+    }
+    if let Err(err) = song_cache::save_metadata(&songs_dir, &song) {
+        eprintln!("[CACHE] couldn't save metadata of {}: {}", id, err);
+    }
+
+    let processing = audio::process_download(&songs_dir, id);
+    song_cache::record(id);
+    // cached songs had their clips cut when they were processed
+    if processing == audio::Processing::Fresh && PRE_CUT_SNIPPETS {
+        snippets::pre_cut(&song);
+    }
+    Ok(song)
+}
+
+/// Name of the file that should be played for a song, relative to the songs route.
+/// Prefers the processed variant and falls back to the original download.
+pub fn playable_file(id: &str) -> String {
+    match song_cache::is_processed_song(id) {
+        true => audio::processed_file_name(id),
+        false => id.to_owned(),
+    }
+}
+
+#[derive(Debug)]
+pub enum OneOrMoreSongs {
+    One(Song),
+    More(Vec<Song>),
+}
+
+pub fn get_one_or_more_songs_from_id(
+    id: &str,
+    requester: DownloadRequester,
+) -> Result<OneOrMoreSongs, GettingSongError> {
+    match source_for_id(id)?.expand(id)? {
+        Some(collection) => {
+            cache_metadata(&collection);
+            let songs = songs_from_collection(collection, &requester)?;
+            Ok(OneOrMoreSongs::More(songs))
+        }
+        None => Ok(OneOrMoreSongs::One(download_queue::download(
+            id, requester,
+        )?)),
+    }
+}
+
+/// Downloads a song, or every song of a collection, to have it ready before a game
+pub fn prefetch(
+    id: &str,
+    requester: DownloadRequester,
+) -> Result<Vec<Result<Song, GettingSongError>>, GettingSongError> {
+    match source_for_id(id)?.expand(id)? {
+        Some(collection) => {
+            cache_metadata(&collection);
+            let downloads = collection
+                .iter()
+                .map(|song| download_queue::enqueue(&song.id, requester.clone()))
+                .collect::<Vec<_>>();
+            Ok(downloads
+                .into_iter()
+                .map(|download| download.recv().unwrap_or(Err(GettingSongError::OtherError)))
+                .collect())
+        }
+        None => Ok(vec![download_queue::download(id, requester)]),
+    }
+}
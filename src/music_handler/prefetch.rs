@@ -0,0 +1,170 @@
+use std::{collections::HashMap, fs, io, path::PathBuf, sync::Mutex, thread};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+
+use super::{download_queue, song_cache, songs_dir, source_for_id};
+
+/// how many songs popularity is kept for, the least added ones are forgotten first
+const TRACKED_SONGS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+struct Popularity {
+    /// how often the song was added to a game
+    count: u32,
+    /// position of the last add among all adds, breaks ties when a song has to be forgotten
+    last_added: u64,
+}
+
+/// How often each song was added to a game, kept across restarts
+static POPULARITY: Lazy<Mutex<HashMap<String, Popularity>>> = Lazy::new(|| {
+    let counts = fs::read(popularity_path())
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default();
+    Mutex::new(counts)
+});
+
+/// stored with the metadata sidecars, which all end in `.json`
+const POPULARITY_FILE: &str = "popularity";
+
+fn popularity_path() -> PathBuf {
+    songs_dir()
+        .join(song_cache::METADATA_DIR)
+        .join(POPULARITY_FILE)
+}
+
+fn save_popularity(counts: &HashMap<String, Popularity>) -> io::Result<()> {
+    let path = popularity_path();
+    fs::create_dir_all(path.parent().unwrap())?;
+    let partial = path.with_extension("part");
+    fs::write(&partial, serde_json::to_vec(counts)?)?;
+    fs::rename(partial, path)
+}
+
+/// Counts songs that were added to a game and forgets the least added ones,
+/// of those the ones that weren't added for the longest time
+fn count_adds(counts: &mut HashMap<String, Popularity>, ids: &[String], capacity: usize) {
+    let mut last_added = counts
+        .values()
+        .map(|popularity| popularity.last_added)
+        .max()
+        .unwrap_or(0);
+    for id in ids {
+        last_added += 1;
+        let popularity = counts.entry(id.clone()).or_default();
+        popularity.count += 1;
+        popularity.last_added = last_added;
+    }
+    while counts.len() > capacity {
+        let Some(least) = counts
+            .iter()
+            .min_by_key(|(_, popularity)| (popularity.count, popularity.last_added))
+            .map(|(id, _)| id.clone())
+        else {
+            break;
+        };
+        counts.remove(&least);
+    }
+}
+
+/// Counts songs that were added to a game, saved with a single write.
+/// Don't call it while holding the games lock, it writes to disk.
+pub fn record_adds(ids: &[String]) {
+    if ids.is_empty() {
+        return;
+    }
+    let mut counts = POPULARITY.lock().unwrap();
+    count_adds(&mut counts, ids, TRACKED_SONGS);
+    if let Err(err) = save_popularity(&counts) {
+        eprintln!("[PREFETCH] couldn't save popularity: {}", err);
+    }
+}
+
+/// The `count` most added songs, most added first
+pub fn most_added(count: usize) -> Vec<String> {
+    let counts = POPULARITY.lock().unwrap();
+    let mut ranked = counts
+        .iter()
+        .map(|(id, popularity)| (id, popularity.count))
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    ranked
+        .into_iter()
+        .take(count)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// The configured songs, with collections expanded, and the most added songs
+fn warm_songs() -> Vec<String> {
+    let options = &config().prefetch;
+    let mut ids = vec![];
+    for id in &options.ids {
+        let expanded = source_for_id(id).and_then(|source| source.expand(id));
+        match expanded {
+            Ok(Some(collection)) => {
+                super::cache_metadata(&collection);
+                ids.extend(collection.into_iter().map(|song| song.id));
+            }
+            Ok(None) => ids.push(id.clone()),
+            Err(err) => eprintln!("[PREFETCH] couldn't expand {}: {:?}", id, err),
+        }
+    }
+    for id in most_added(options.popular_count) {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Keeps the warm songs from being evicted and downloads the missing ones in the background
+pub fn warm_up() {
+    let ids = warm_songs();
+    let missing = ids
+        .iter()
+        .filter(|id| !song_cache::contains(id))
+        .collect::<Vec<_>>();
+    println!(
+        "[PREFETCH] keeping {} songs warm, {} to download",
+        ids.len(),
+        missing.len()
+    );
+    missing
+        .iter()
+        .for_each(|id| download_queue::enqueue_background(id));
+    song_cache::set_warm(ids);
+}
+
+/// Warms the cache up now and again every `prefetch.interval`
+pub fn start() {
+    let interval = config().prefetch.interval;
+    thread::spawn(move || loop {
+        warm_up();
+        thread::sleep(interval);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_added_songs_are_forgotten_oldest_first() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let mut counts = HashMap::new();
+        count_adds(&mut counts, &ids(&["a", "b"]), 2);
+        count_adds(&mut counts, &ids(&["c"]), 2);
+        assert!(!counts.contains_key("a"));
+        assert!(counts.contains_key("c"));
+
+        count_adds(&mut counts, &ids(&["c", "d"]), 2);
+        let mut left = counts.keys().cloned().collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["c", "d"]);
+        assert_eq!(counts["c"].count, 2);
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::model::search_result::SearchResult;
+
+/// Lower case, without surrounding quotes and with single spaces,
+/// so queries that only differ in how they were typed share their results
+pub fn normalize(query: &str) -> String {
+    query
+        .trim()
+        .trim_matches(['"', '\''])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+struct Entry {
+    results: Vec<SearchResult>,
+    inserted: Instant,
+    /// tick of the last lookup, the entry with the lowest one is evicted first
+    used: u64,
+}
+
+/// Search results by normalized query, bounded in size and age
+pub struct QueryCache {
+    entries: HashMap<String, Entry>,
+    capacity: usize,
+    ttl: Duration,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Lookups since the server started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn is_fresh(&self, entry: &Entry) -> bool {
+        entry.inserted.elapsed() < self.ttl
+    }
+
+    /// The results of a query if they are cached and haven't expired
+    pub fn get(&mut self, key: &str) -> Option<Vec<SearchResult>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let ttl = self.ttl;
+        let found = match self.entries.get_mut(key) {
+            Some(entry) if entry.inserted.elapsed() < ttl => {
+                entry.used = tick;
+                Some(entry.results.clone())
+            }
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        match found {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        found
+    }
+
+    /// Caches the results of a query, dropping expired entries and
+    /// then the least recently used ones when the cache is full
+    pub fn insert(&mut self, key: String, results: Vec<SearchResult>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, entry| entry.inserted.elapsed() < ttl);
+            while self.entries.len() >= self.capacity {
+                let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                results,
+                inserted: Instant::now(),
+                used: self.tick,
+            },
+        );
+    }
+
+    /// The results of every query that hasn't expired
+    pub fn values(&self) -> impl Iterator<Item = &Vec<SearchResult>> {
+        self.entries
+            .values()
+            .filter(|entry| self.is_fresh(entry))
+            .map(|entry| &entry.results)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: &str) -> Vec<SearchResult> {
+        vec![SearchResult {
+            name: id.to_string(),
+            id: id.to_string(),
+            r#type: "video".to_string(),
+        }]
+    }
+
+    #[test]
+    fn keys_ignore_case_quotes_and_spacing() {
+        assert_eq!(normalize("  \"Daft   Punk\" "), "daft punk");
+        assert_eq!(normalize("daft punk"), normalize("DAFT PUNK"));
+    }
+
+    #[test]
+    fn least_recently_used_query_is_evicted() {
+        let mut cache = QueryCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), result("a"));
+        cache.insert("b".to_string(), result("b"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), result("c"));
+
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                entries: 2
+            }
+        );
+    }
+
+    #[test]
+    fn expired_results_are_not_returned() {
+        let mut cache = QueryCache::new(2, Duration::ZERO);
+        cache.insert("a".to_string(), result("a"));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.values().count(), 0);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{audio, config::config, game::snippets::CLIPS_DIR, model::song::Song};
+
+use super::songs_dir;
+
+/// A downloaded song in the songs directory
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub id: String,
+    /// size of the download, its processed variant and its clips, in bytes
+    pub size: u64,
+    pub processed: bool,
+    /// when the song was last played or added to a game, or downloaded without metadata
+    pub modified: SystemTime,
+    /// `None` for songs downloaded before metadata was kept
+    pub song: Option<Song>,
+}
+
+/// Subdirectory with a `<id>.json` sidecar per downloaded song, and other state of the cache
+pub const METADATA_DIR: &str = "metadata";
+
+fn metadata_path(songs_dir: &Path, id: &str) -> PathBuf {
+    songs_dir.join(METADATA_DIR).join(format!("{}.json", id))
+}
+
+fn read_metadata(path: &Path) -> Option<Song> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+/// Stores the metadata of a downloaded song, written to a partial file first
+/// so a crash never leaves a truncated sidecar
+pub fn save_metadata(songs_dir: &Path, song: &Song) -> io::Result<()> {
+    let path = metadata_path(songs_dir, &song.id);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let partial = path.with_file_name(format!("{}.json.part", song.id));
+    fs::write(&partial, serde_json::to_vec(song)?)?;
+    fs::rename(partial, path)
+}
+
+/// The metadata of every downloaded song that has it. Sidecars that can't be read
+/// or whose song is gone are deleted, so the cache only knows files that exist.
+pub fn load_metadata(songs_dir: &Path) -> Vec<Song> {
+    let Ok(dir) = fs::read_dir(songs_dir.join(METADATA_DIR)) else {
+        return vec![];
+    };
+    dir.flatten()
+        // partial sidecars are left to the sweep, other files aren't sidecars
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".json"))
+        .filter_map(|entry| {
+            let path = entry.path();
+            let song = read_metadata(&path).filter(|song| {
+                metadata_path(songs_dir, &song.id) == path && songs_dir.join(&song.id).exists()
+            });
+            if song.is_none() {
+                eprintln!("[CACHE] removing stale metadata {}", path.display());
+                let _ = fs::remove_file(&path);
+            }
+            song
+        })
+        .collect()
+}
+
+/// unfinished downloads and processing runs
+fn is_partial(file_name: &str) -> bool {
+    file_name.ends_with(".part")
+}
+
+fn is_processed(file_name: &str) -> bool {
+    file_name.ends_with(&format!(".{}", audio::PROCESSED_SUFFIX))
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+/// The song a clip was cut from, clips are named `<file>-<offset>-<length>`,
+/// the file being the download or its processed variant
+fn clip_song_id(clip_name: &str) -> Option<&str> {
+    let song_file = clip_name.rsplitn(3, '-').nth(2)?;
+    Some(
+        song_file
+            .strip_suffix(&format!(".{}", audio::PROCESSED_SUFFIX))
+            .unwrap_or(song_file),
+    )
+}
+
+/// The finished clips in the songs directory with the ID of their song
+fn clips_in(songs_dir: &Path) -> Vec<(String, fs::DirEntry)> {
+    let Ok(dir) = fs::read_dir(songs_dir.join(CLIPS_DIR)) else {
+        return vec![];
+    };
+    dir.flatten()
+        .filter_map(|clip| {
+            let name = clip.file_name().to_string_lossy().to_string();
+            if is_partial(&name) {
+                return None;
+            }
+            Some((clip_song_id(&name)?.to_owned(), clip))
+        })
+        .collect()
+}
+
+/// The downloaded songs, sorted by ID
+pub fn entries() -> Vec<CacheEntry> {
+    entries_in(&songs_dir())
+}
+
+fn entries_in(songs_dir: &Path) -> Vec<CacheEntry> {
+    let Ok(dir) = fs::read_dir(songs_dir) else {
+        return vec![];
+    };
+    let mut clip_sizes = HashMap::<String, u64>::new();
+    for (id, clip) in clips_in(songs_dir) {
+        *clip_sizes.entry(id).or_default() += file_size(&clip.path());
+    }
+    let mut entries = dir
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter_map(|entry| {
+            let id = entry.file_name().to_string_lossy().to_string();
+            if is_partial(&id) || is_processed(&id) {
+                return None;
+            }
+            let processed = songs_dir.join(audio::processed_file_name(&id));
+            let metadata = metadata_path(songs_dir, &id);
+            Some(CacheEntry {
+                size: file_size(&entry.path())
+                    + file_size(&processed)
+                    + clip_sizes.get(&id).copied().unwrap_or(0),
+                processed: processed.exists(),
+                modified: fs::metadata(&metadata)
+                    .or_else(|_| entry.metadata())
+                    .and_then(|m| m.modified())
+                    .ok()?,
+                song: read_metadata(&metadata),
+                id,
+            })
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    entries
+}
+
+/// Size of a downloaded song and whether it was processed
+#[derive(Debug, Clone, Copy)]
+struct IndexedSong {
+    size: u64,
+    processed: bool,
+}
+
+/// The downloaded songs by ID, so lookups don't scan the songs directory.
+/// Built on first use, updated on downloads and removals and rebuilt by every sweep
+/// to pick up changes made by other processes.
+static INDEX: Lazy<RwLock<HashMap<String, IndexedSong>>> =
+    Lazy::new(|| RwLock::new(index_of(&entries())));
+
+fn index_of(entries: &[CacheEntry]) -> HashMap<String, IndexedSong> {
+    entries
+        .iter()
+        .map(|entry| {
+            let indexed = IndexedSong {
+                size: entry.size,
+                processed: entry.processed,
+            };
+            (entry.id.clone(), indexed)
+        })
+        .collect()
+}
+
+/// Whether the song was downloaded
+pub fn contains(id: &str) -> bool {
+    INDEX.read().unwrap().contains_key(id)
+}
+
+/// Whether the song has a processed variant
+pub fn is_processed_song(id: &str) -> bool {
+    INDEX
+        .read()
+        .unwrap()
+        .get(id)
+        .is_some_and(|song| song.processed)
+}
+
+/// Adds a finished download to the index, after processing it
+pub fn record(id: &str) {
+    let songs_dir = songs_dir();
+    let processed = songs_dir.join(audio::processed_file_name(id));
+    let indexed = IndexedSong {
+        size: file_size(&songs_dir.join(id)) + file_size(&processed),
+        processed: processed.exists(),
+    };
+    INDEX.write().unwrap().insert(id.to_owned(), indexed);
+}
+
+/// Drops a song from the index whose download is gone
+pub fn forget(id: &str) {
+    INDEX.write().unwrap().remove(id);
+}
+
+/// Number and total size of the downloaded songs
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSize {
+    pub songs: usize,
+    pub bytes: u64,
+}
+
+pub fn size() -> CacheSize {
+    let index = INDEX.read().unwrap();
+    CacheSize {
+        songs: index.len(),
+        bytes: index.values().map(|song| song.size).sum(),
+    }
+}
+
+/// What pruning removed
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl PruneReport {
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let size = file_size(path);
+        fs::remove_file(path)?;
+        self.files += 1;
+        self.bytes += size;
+        Ok(())
+    }
+
+    /// Removes a song with its processed variant, metadata and clips
+    fn remove_song(&mut self, songs_dir: &Path, id: &str) -> io::Result<()> {
+        let mut paths = vec![
+            songs_dir.join(audio::processed_file_name(id)),
+            metadata_path(songs_dir, id),
+        ];
+        paths.extend(
+            clips_in(songs_dir)
+                .into_iter()
+                .filter(|(clip_id, _)| clip_id == id)
+                .map(|(_, clip)| clip.path()),
+        );
+        for path in paths {
+            if path.exists() {
+                self.remove(&path)?;
+            }
+        }
+        INDEX.write().unwrap().remove(id);
+        self.remove(&songs_dir.join(id))
+    }
+
+    /// Removes partial downloads, clips and sidecars that weren't written to for `min_age`,
+    /// younger ones may still be in progress
+    fn remove_partials(&mut self, songs_dir: &Path, min_age: Duration) -> io::Result<()> {
+        let metadata_dir = songs_dir.join(METADATA_DIR);
+        let clips_dir = songs_dir.join(CLIPS_DIR);
+        for dir in [songs_dir, &metadata_dir, &clips_dir] {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let age = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(Duration::MAX, |modified| {
+                        modified.elapsed().unwrap_or_default()
+                    });
+                if is_partial(&entry.file_name().to_string_lossy()) && age >= min_age {
+                    self.remove(&entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes clips of songs that are gone, clips cut from the download of a song
+    /// that was processed since, and clips that weren't played for `max_age`.
+    /// Clips of songs in games are kept.
+    fn remove_stale_clips(&mut self, songs_dir: &Path, max_age: Duration) -> io::Result<()> {
+        for (id, clip) in clips_in(songs_dir) {
+            if in_game(&id) {
+                continue;
+            }
+            let name = clip.file_name().to_string_lossy().to_string();
+            let age = clip
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map_or(Duration::MAX, |modified| {
+                    modified.elapsed().unwrap_or_default()
+                });
+            let superseded = !name.starts_with(&audio::processed_file_name(&id))
+                && songs_dir.join(audio::processed_file_name(&id)).exists();
+            if !songs_dir.join(&id).exists() || superseded || age >= max_age {
+                self.remove(&clip.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the sidecars of songs that are gone
+    fn remove_orphaned_metadata(&mut self, songs_dir: &Path) -> io::Result<()> {
+        let Ok(entries) = fs::read_dir(songs_dir.join(METADATA_DIR)) else {
+            return Ok(());
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(id) = name.strip_suffix(".json") {
+                if !songs_dir.join(id).exists() {
+                    self.remove(&entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes partial downloads, metadata of missing songs and the cut clips,
+/// which are cut again when needed.
+/// With `older_than` songs that weren't played or downloaded within that time are removed too.
+pub fn prune(older_than: Option<Duration>) -> io::Result<PruneReport> {
+    let songs_dir = songs_dir();
+    let mut report = PruneReport::default();
+    if !songs_dir.exists() {
+        return Ok(report);
+    }
+
+    report.remove_partials(&songs_dir, Duration::ZERO)?;
+    let clips_dir = songs_dir.join(CLIPS_DIR);
+    if clips_dir.exists() {
+        for entry in fs::read_dir(&clips_dir)?.flatten() {
+            report.remove(&entry.path())?;
+        }
+    }
+
+    if let Some(max_age) = older_than {
+        let now = SystemTime::now();
+        for entry in entries() {
+            let age = now.duration_since(entry.modified).unwrap_or_default();
+            if age > max_age {
+                report.remove_song(&songs_dir, &entry.id)?;
+            }
+        }
+    }
+
+    report.remove_orphaned_metadata(&songs_dir)?;
+    Ok(report)
+}
+
+/// IDs of the songs whose download or processed variant can't be read as audio
+pub fn verify() -> Vec<String> {
+    let songs_dir = songs_dir();
+    entries()
+        .into_iter()
+        .filter(|entry| {
+            let processed = songs_dir.join(audio::processed_file_name(&entry.id));
+            !audio::is_readable(&songs_dir.join(&entry.id))
+                || (entry.processed && !audio::is_readable(&processed))
+        })
+        .map(|entry| entry.id)
+        .collect()
+}
+
+/// Deletes a song with its processed variant, metadata and clips
+pub fn remove(id: &str) -> io::Result<()> {
+    PruneReport::default().remove_song(&songs_dir(), id)
+}
+
+/// Songs used by games, by game ID. They are never evicted.
+static PINNED: Lazy<Mutex<HashMap<u16, HashSet<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Keeps a song until `unpin_game` is called for the game
+pub fn pin(game_id: u16, id: &str) {
+    PINNED
+        .lock()
+        .unwrap()
+        .entry(game_id)
+        .or_default()
+        .insert(id.to_owned());
+}
+
+/// Releases the songs of a game that ended or was closed
+pub fn unpin_game(game_id: u16) {
+    PINNED.lock().unwrap().remove(&game_id);
+}
+
+/// Songs that should stay downloaded because they are played often
+static WARM: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+/// Replaces the songs that are never evicted because they are played often
+pub fn set_warm(ids: Vec<String>) {
+    *WARM.write().unwrap() = ids.into_iter().collect();
+}
+
+/// Whether a game uses the song
+fn in_game(id: &str) -> bool {
+    PINNED.lock().unwrap().values().any(|ids| ids.contains(id))
+}
+
+fn is_pinned(id: &str) -> bool {
+    WARM.read().unwrap().contains(id) || in_game(id)
+}
+
+/// Whether a file of the songs directory, given relative to it, may be served as is.
+/// Metadata and unfinished files never are, songs and their clips only while
+/// no game uses them, since their names give the song away.
+pub fn is_servable(file: &Path) -> bool {
+    let Some(names) = file
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+    let song_file = match names.as_slice() {
+        [name] => name.as_str(),
+        // clips are named `<file>-<offset>-<length>`
+        [dir, clip] if dir == CLIPS_DIR && !is_partial(clip) => match clip_song_id(clip) {
+            Some(id) => id,
+            None => return false,
+        },
+        _ => return false,
+    };
+    if is_partial(song_file) {
+        return false;
+    }
+    let id = song_file
+        .strip_suffix(&format!(".{}", audio::PROCESSED_SUFFIX))
+        .unwrap_or(song_file);
+    !in_game(id)
+}
+
+/// Marks a clip as just played, clips that weren't played for a while are swept
+pub fn touch_clip(path: &Path) {
+    let played = fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = played {
+        eprintln!(
+            "[CACHE] couldn't mark {} as played: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Marks a song as just played by updating the modification time of its metadata,
+/// not of the download, which can be a hard link into the local library.
+/// Songs that weren't played for the longest time are evicted first.
+pub fn touch(id: &str) {
+    let played = fs::File::options()
+        .write(true)
+        .open(metadata_path(&songs_dir(), id))
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = played {
+        eprintln!("[CACHE] couldn't mark {} as played: {}", id, err);
+    }
+}
+
+/// Evicts the least recently played songs that aren't pinned
+/// until the downloads take up at most `max_bytes`
+pub fn enforce_quota(max_bytes: u64) -> io::Result<PruneReport> {
+    evict(&songs_dir(), max_bytes)
+}
+
+fn evict(songs_dir: &Path, max_bytes: u64) -> io::Result<PruneReport> {
+    let mut report = PruneReport::default();
+    let mut entries = entries_in(songs_dir);
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+    entries.sort_by_key(|entry| entry.modified);
+    for entry in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if is_pinned(&entry.id) {
+            continue;
+        }
+        report.remove_song(songs_dir, &entry.id)?;
+        total = total.saturating_sub(entry.size);
+    }
+    if total > max_bytes {
+        eprintln!(
+            "[CACHE] songs in games or kept warm take up {} MiB, more than the quota of {} MiB",
+            total / 1024 / 1024,
+            max_bytes / 1024 / 1024
+        );
+    }
+    Ok(report)
+}
+
+/// partial files younger than this might still be written by yt-dlp or ffmpeg
+const PARTIAL_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// clips that weren't played for this long are removed, they are cut again when needed
+const CLIP_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Removes leftovers of interrupted downloads and keeps the cache within its quota
+pub fn sweep() -> io::Result<PruneReport> {
+    let songs_dir = songs_dir();
+    let mut report = PruneReport::default();
+    report.remove_partials(&songs_dir, PARTIAL_GRACE_PERIOD)?;
+    report.remove_stale_clips(&songs_dir, CLIP_MAX_AGE)?;
+    report.remove_orphaned_metadata(&songs_dir)?;
+
+    *INDEX.write().unwrap() = index_of(&entries());
+    let max_size_mb = config().cache.max_size_mb;
+    if max_size_mb > 0 && size().bytes > max_size_mb * 1024 * 1024 {
+        let evicted = enforce_quota(max_size_mb * 1024 * 1024)?;
+        report.files += evicted.files;
+        report.bytes += evicted.bytes;
+    }
+    Ok(report)
+}
+
+pub fn start_sweeper() {
+    let interval = config().cache.sweep_interval;
+    println!("[CACHE] sweeping the songs cache every {:?}", interval);
+    thread::spawn(move || loop {
+        match sweep() {
+            Ok(report) if report.files > 0 => println!(
+                "[CACHE] sweep removed {} files, {} bytes",
+                report.files, report.bytes
+            ),
+            Ok(_) => {}
+            Err(err) => eprintln!("[CACHE] sweep failed: {}", err),
+        }
+        thread::sleep(interval);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_is_kept_for_existing_songs_only() {
+        let song = Song {
+            id: "mock:sidecar".to_string(),
+            title: "Sidecar".to_string(),
+            artist: "The Placeholders".to_string(),
+            length: 2,
+            published: 0,
+            album: Some("Tests".to_string()),
+            release_year: None,
+        };
+        let songs_dir = std::env::temp_dir().join(format!("gts-sidecars-{}", std::process::id()));
+        fs::create_dir_all(&songs_dir).unwrap();
+        fs::write(songs_dir.join(&song.id), b"audio").unwrap();
+        save_metadata(&songs_dir, &song).unwrap();
+
+        let loaded = load_metadata(&songs_dir);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].album.as_deref(), Some("Tests"));
+
+        fs::remove_file(songs_dir.join(&song.id)).unwrap();
+        let loaded = load_metadata(&songs_dir);
+        let sidecar_left = metadata_path(&songs_dir, &song.id).exists();
+        fs::remove_dir_all(&songs_dir).unwrap();
+
+        assert!(loaded.is_empty());
+        assert!(!sidecar_left);
+    }
+
+    #[test]
+    fn least_recently_played_unpinned_songs_are_evicted() {
+        let songs_dir = std::env::temp_dir().join(format!("gts-quota-{}", std::process::id()));
+        fs::create_dir_all(songs_dir.join(METADATA_DIR)).unwrap();
+        let now = SystemTime::now();
+        for (idx, id) in ["played", "pinned", "old"].iter().enumerate() {
+            fs::write(songs_dir.join(id), [0; 100]).unwrap();
+            let metadata = metadata_path(&songs_dir, id);
+            fs::write(&metadata, b"{}").unwrap();
+            let played = now - Duration::from_secs(60 * idx as u64);
+            fs::File::options()
+                .write(true)
+                .open(metadata)
+                .and_then(|file| file.set_modified(played))
+                .unwrap();
+        }
+        pin(u16::MAX, "pinned");
+
+        let report = evict(&songs_dir, 150).unwrap();
+        unpin_game(u16::MAX);
+        let left = entries_in(&songs_dir)
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&songs_dir).unwrap();
+
+        assert_eq!(left, ["pinned"]);
+        assert_eq!(report.files, 4);
+    }
+
+    #[test]
+    fn stale_clips_are_swept_and_counted_until_then() {
+        let songs_dir = std::env::temp_dir().join(format!("gts-clips-{}", std::process::id()));
+        let clips_dir = songs_dir.join(CLIPS_DIR);
+        fs::create_dir_all(&clips_dir).unwrap();
+        fs::write(songs_dir.join("test:kept"), [0; 100]).unwrap();
+        fs::write(songs_dir.join("test:kept.norm"), [0; 100]).unwrap();
+        for clip in [
+            "test:kept.norm-0-30",
+            "test:kept.norm-60-30",
+            "test:kept-0-30",
+            "test:gone-0-30",
+            "test:clipped-0-30",
+        ] {
+            fs::write(clips_dir.join(clip), [0; 10]).unwrap();
+        }
+        let unplayed = SystemTime::now() - Duration::from_secs(120);
+        fs::File::options()
+            .write(true)
+            .open(clips_dir.join("test:kept.norm-60-30"))
+            .and_then(|file| file.set_modified(unplayed))
+            .unwrap();
+        pin(u16::MAX - 2, "test:clipped");
+
+        let size_before = entries_in(&songs_dir)[0].size;
+        let mut report = PruneReport::default();
+        report
+            .remove_stale_clips(&songs_dir, Duration::from_secs(60))
+            .unwrap();
+        unpin_game(u16::MAX - 2);
+        let mut left = fs::read_dir(&clips_dir)
+            .unwrap()
+            .flatten()
+            .map(|clip| clip.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        left.sort();
+        let size_after = entries_in(&songs_dir)[0].size;
+        fs::remove_dir_all(&songs_dir).unwrap();
+
+        assert_eq!(size_before, 230);
+        assert_eq!(left, ["test:clipped-0-30", "test:kept.norm-0-30"]);
+        assert_eq!(report.files, 3);
+        assert_eq!(size_after, 210);
+    }
+
+    #[test]
+    fn files_of_songs_in_games_are_not_served() {
+        pin(u16::MAX - 1, "test:playing");
+        let servable = |file: &str| is_servable(Path::new(file));
+        assert!(servable("test:free"));
+        assert!(servable("test:free.norm"));
+        assert!(servable("clips/test:free.norm-12-30"));
+        assert!(!servable("test:playing"));
+        assert!(!servable("test:playing.norm"));
+        assert!(!servable("clips/test:playing-12-30"));
+        assert!(!servable("metadata/test:free.json"));
+        assert!(!servable("test:free.part"));
+        assert!(!servable("../test:free"));
+        unpin_game(u16::MAX - 1);
+        assert!(servable("test:playing"));
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use crate::model::{
+    search_result::SearchResult,
+    song::{GettingSongError, Song},
+};
+
+/// What a search found
+#[derive(Default)]
+pub struct SearchResults {
+    pub items: Vec<SearchResult>,
+    /// metadata of the songs among the items, if the search already provides it
+    pub songs: Vec<Song>,
+}
+
+/// A backend songs can be searched for and downloaded from
+pub trait MusicSource: Send + Sync {
+    /// name used to select the source in the configuration
+    fn name(&self) -> &'static str;
+
+    /// called once at startup, the source should be usable when it returns
+    fn start(&self) {}
+
+    /// whether `id` is an ID of this source, IDs of different sources must not overlap
+    fn owns_id(&self, id: &str) -> bool;
+
+    fn search(&self, query: &str) -> Result<SearchResults, GettingSongError>;
+
+    /// metadata of a single song
+    fn resolve(&self, id: &str) -> Result<Song, GettingSongError>;
+
+    /// The songs of a collection (playlist, channel, album),
+    /// `None` if `id` is a single song
+    fn expand(&self, id: &str) -> Result<Option<Vec<Song>>, GettingSongError>;
+
+    /// Stores the audio of a song in `songs_dir`, in a file named after its ID
+    fn fetch_audio(&self, id: &str, songs_dir: &Path) -> Result<(), GettingSongError>;
+}
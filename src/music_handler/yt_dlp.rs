@@ -0,0 +1,88 @@
+use std::{
+    io::ErrorKind,
+    path::Path,
+    process::{self, Stdio},
+};
+
+use crate::{config::config, model::song::GettingSongError};
+
+/// Maps yt-dlp's output to the reason a download failed
+fn classify(output: &str) -> Option<GettingSongError> {
+    let output = output.to_lowercase();
+    let mentions = |patterns: &[&str]| patterns.iter().any(|p| output.contains(p));
+
+    if mentions(&["larger than max-filesize"]) {
+        Some(GettingSongError::TooLarge)
+    } else if mentions(&[
+        "not available in your country",
+        "blocked it in your country",
+        "geo restriction",
+    ]) {
+        Some(GettingSongError::GeoBlocked)
+    } else if mentions(&["confirm your age", "age-restricted", "age restricted"]) {
+        Some(GettingSongError::AgeRestricted)
+    } else if mentions(&[
+        "video unavailable",
+        "private video",
+        "has been removed",
+        "requested format is not available",
+    ]) {
+        Some(GettingSongError::Unavailable)
+    } else {
+        None
+    }
+}
+
+/// Downloads the audio of a video into `songs_dir`, named after its ID
+pub fn download(songs_dir: &Path, id: &str) -> Result<(), GettingSongError> {
+    let options = &config().yt_dlp;
+    let output = process::Command::new("yt-dlp")
+        .current_dir(songs_dir)
+        .args([
+            "-f",
+            &options.format,
+            "--max-filesize",
+            &options.max_filesize,
+            "--no-progress",
+            "-o",
+            "%(id)s",
+            "--",
+            id,
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => GettingSongError::YtDlpMissing,
+            _ => GettingSongError::from(e),
+        })?;
+
+    // yt-dlp reports some failures (like a too large file) on stdout and still exits with 0
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    #[cfg(debug_assertions)]
+    print!("{}", stdout);
+
+    if output.status.success() && songs_dir.join(id).exists() {
+        println!("yt-dlp download successful, filename: {}", id);
+        return Ok(());
+    }
+
+    eprintln!(
+        "yt-dlp download of {} failed ({}): {}",
+        id,
+        output.status,
+        stderr.trim()
+    );
+    Err(classify(&stderr)
+        .or_else(|| classify(&stdout))
+        .unwrap_or_else(|| {
+            GettingSongError::YtDlpFailed(
+                stderr
+                    .lines()
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                    .unwrap_or("no file was written")
+                    .to_string(),
+            )
+        }))
+}
@@ -0,0 +1,225 @@
+use super::{http_head, http_status, TestClient};
+
+/// Two players in the same lobby, alice leads
+fn lobby_of_two() -> (TestClient, TestClient) {
+    let mut alice = TestClient::connect("alice");
+    let game_id = alice.create_game();
+    let mut bob = TestClient::connect("bob");
+    bob.send(&format!("join {}", game_id));
+    bob.expect(&[
+        "user_join \"alice\"",
+        "question_mode title_artist",
+        "snippet_mode full",
+    ]);
+    alice.expect(&["user_join \"bob\""]);
+    (alice, bob)
+}
+
+fn ready_up(alice: &mut TestClient, bob: &mut TestClient) {
+    alice.send("ready_up");
+    alice.expect(&["user_ready \"alice\"", "k"]);
+    bob.expect(&["user_ready \"alice\""]);
+
+    bob.send("ready_up");
+    bob.expect(&[
+        "user_ready \"bob\"",
+        "game_start_at *",
+        "k",
+        "game_start_select",
+    ]);
+    alice.expect(&["user_ready \"bob\"", "game_start_at *", "game_start_select"]);
+}
+
+/// Adds a single song, download progress comes from the queue's threads in any order
+fn add_song(adder: &mut TestClient, others: &mut [&mut TestClient], id: &str, added: &str) {
+    adder.send(&format!("add {}", id));
+    let expected = [
+        "selection_progress *".to_string(),
+        "k".to_string(),
+        format!("download_status {} queued *", id),
+        format!("download_status {} downloading", id),
+        format!("download_status {} done", id),
+        format!("added_song {}", added),
+        "selection_progress *".to_string(),
+    ];
+    adder.expect_unordered(&expected.iter().map(String::as_str).collect::<Vec<_>>());
+    for other in others {
+        other.expect(&["selection_progress *", "selection_progress *"]);
+    }
+}
+
+/// Starts guessing and returns the audio token of the first round
+fn start_guessing(alice: &mut TestClient, others: &mut [&mut TestClient]) -> String {
+    alice.send("start_guessing");
+    let token = preload_token(&alice.expect(&["game_start_guessing", "game_preload 0 *"])[1]);
+    for other in others {
+        let messages = other.expect(&["game_start_guessing", "game_preload 0 *"]);
+        assert_eq!(preload_token(&messages[1]), token);
+    }
+    token
+}
+
+fn preload_token(message: &str) -> String {
+    message.rsplit(' ').next().unwrap().to_string()
+}
+
+fn load_round(player: &mut TestClient, round: u16) {
+    player.send(&format!("loaded {}", round));
+}
+
+fn expect_round_start(player: &mut TestClient, token: &str) {
+    player.expect(&[
+        &format!("game_play_audio {} 0 2000 *", token),
+        "game_question title_artist",
+        "game_guess_options *",
+    ]);
+}
+
+#[test]
+fn lobby_join_modes_and_ready() {
+    let (mut alice, mut bob) = lobby_of_two();
+
+    alice.send("question_mode title");
+    alice.expect(&["question_mode title", "k"]);
+    bob.expect(&["question_mode title"]);
+
+    bob.send("snippet_mode random");
+    bob.expect(&[r##"ERR ""cannot set snippet mode: you are not the leader"""##]);
+
+    let mut carol = TestClient::connect("carol");
+    carol.send("join 0");
+    carol.expect(&["game_not_found"]);
+
+    ready_up(&mut alice, &mut bob);
+
+    alice.send("question_mode artist");
+    alice.expect(&[r##"ERR ""cannot set question mode: game is not in lobby state"""##]);
+}
+
+#[test]
+fn full_game() {
+    let (mut alice, mut bob) = lobby_of_two();
+    ready_up(&mut alice, &mut bob);
+
+    alice.send("suggest lumen");
+    alice.expect(&[concat!(
+        "suggestions [",
+        r#"{"name":"Golden Hour","id":"mock:6","type":"video"},"#,
+        r#"{"name":"Last Train Home","id":"mock:7","type":"video"},"#,
+        r#"{"name":"Mock Playlist","id":"mock:playlist","type":"playlist"}]"#
+    )]);
+
+    add_song(
+        &mut alice,
+        &mut [&mut bob],
+        "mock:0",
+        r#"["Morning Tide","The Placeholders"]"#,
+    );
+    add_song(
+        &mut bob,
+        &mut [&mut alice],
+        "mock:1",
+        r#"["Static Hearts","The Placeholders"]"#,
+    );
+
+    bob.send("start_guessing");
+    bob.expect(&[r##"ERR ""cannot start guessing: you are not the leader"""##]);
+
+    let mut token = start_guessing(&mut alice, &mut [&mut bob]);
+    // nothing names the songs of the game
+    for path in ["/songs/mock:0", "/songs/metadata/mock:0.json"] {
+        assert_eq!(http_status(path), 404, "{} was served", path);
+    }
+    for round in 0..2 {
+        let head = http_head(&format!("/audio/{}", token));
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(!head.to_lowercase().contains("content-disposition"));
+        load_round(&mut alice, round);
+        load_round(&mut bob, round);
+        expect_round_start(&mut alice, &token);
+        expect_round_start(&mut bob, &token);
+
+        alice.send("guess 0");
+        bob.send("guess 1");
+        let next_tokens = [&mut alice, &mut bob].map(|player| {
+            let correct = player.expect(&["correct *"]);
+            let correct: u8 = correct[0]["correct ".len()..].parse().unwrap();
+            assert!(correct < 4);
+            if round == 1 {
+                player.expect(&["leaderboard *", "game_ended"]);
+                return None;
+            }
+            let preload =
+                player.expect(&[&format!("game_preload {} *", round + 1), "leaderboard *"]);
+            Some(preload_token(&preload[0]))
+        });
+        assert_eq!(next_tokens[0], next_tokens[1]);
+
+        // tokens stop working once their round is over
+        assert_eq!(http_status(&format!("/audio/{}", token)), 404);
+        if let [Some(next_token), _] = next_tokens {
+            token = next_token;
+        }
+    }
+}
+
+#[test]
+fn leave_mid_round() {
+    let (mut alice, mut bob) = lobby_of_two();
+    ready_up(&mut alice, &mut bob);
+    add_song(
+        &mut alice,
+        &mut [&mut bob],
+        "mock:2",
+        r#"["Neon Avenue","Synthetic Dreams"]"#,
+    );
+    add_song(
+        &mut bob,
+        &mut [&mut alice],
+        "mock:3",
+        r#"["Paper Planes Tonight","Synthetic Dreams"]"#,
+    );
+
+    let token = start_guessing(&mut alice, &mut [&mut bob]);
+    load_round(&mut alice, 0);
+    load_round(&mut bob, 0);
+    expect_round_start(&mut alice, &token);
+    expect_round_start(&mut bob, &token);
+
+    bob.send("leave");
+    bob.expect(&["user_leave \"bob\""]);
+    alice.expect(&["user_leave \"bob\""]);
+
+    // the round ends without waiting for bob's guess, and the next one without his preload
+    alice.send("guess 0");
+    let next = alice.expect(&["correct *", "game_preload 1 *", "leaderboard *"]);
+    let token = preload_token(&next[1]);
+    load_round(&mut alice, 1);
+    expect_round_start(&mut alice, &token);
+    alice.send("guess 0");
+    alice.expect(&["correct *", "leaderboard *", "game_ended"]);
+}
+
+#[test]
+fn round_starts_without_stragglers() {
+    let (mut alice, mut bob) = lobby_of_two();
+    ready_up(&mut alice, &mut bob);
+    add_song(
+        &mut alice,
+        &mut [&mut bob],
+        "mock:4",
+        r#"["Glass Garden","Velvet Echo"]"#,
+    );
+
+    let token = start_guessing(&mut alice, &mut [&mut bob]);
+    load_round(&mut alice, 0);
+    for player in [&mut alice, &mut bob] {
+        player.expect(&["preload_stragglers [\"bob\"]"]);
+        expect_round_start(player, &token);
+    }
+    alice.send("guess 0");
+    bob.send("guess 0");
+    for player in [&mut alice, &mut bob] {
+        player.expect(&["correct *", "leaderboard *", "game_ended"]);
+    }
+}
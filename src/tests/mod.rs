@@ -0,0 +1,228 @@
+//! End-to-end tests of the websocket protocol. They run the actix app in-process
+//! with the mock music source and short game timings.
+
+mod game_flow;
+mod prefetch;
+mod timing;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use actix_web::{App, HttpServer};
+use once_cell::sync::Lazy;
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::{
+    config::{Config, ServerConfig, SourcesConfig},
+    configure_app,
+    game::timings::{set_timings, GameTimings},
+    music_handler,
+};
+
+const TEST_TIMINGS: GameTimings = GameTimings {
+    start_countdown: Duration::from_millis(50),
+    play_start_lead: Duration::from_millis(10),
+    preload_timeout: Duration::from_millis(300),
+    guess_timeout: Duration::from_secs(2),
+    reveal: Duration::from_millis(20),
+    leaderboard: Duration::from_millis(20),
+    game_end: Duration::from_millis(20),
+};
+/// how long a client waits for the next message before the test fails
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What every test runs with, the mock source and a songs directory of their own,
+/// used in place of the defaults the first time the configuration is read
+pub fn test_config() -> Config {
+    Config {
+        server: ServerConfig {
+            songs_dir: test_songs_dir(),
+            ..ServerConfig::default()
+        },
+        sources: SourcesConfig {
+            enabled: vec!["mock".to_string()],
+            ..SourcesConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+/// A fresh directory for every test run, so cached songs don't leak between runs
+fn test_songs_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gts-tests-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Port of the test server, started on first use and shared by all tests
+static SERVER_PORT: Lazy<u16> = Lazy::new(|| {
+    set_timings(TEST_TIMINGS);
+    music_handler::start_sources();
+    music_handler::download_queue::start_download_workers();
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let server = HttpServer::new(|| App::new().configure(configure_app))
+                .workers(2)
+                .bind(("127.0.0.1", 0))
+                .unwrap();
+            tx.send(server.addrs()[0].port()).unwrap();
+            server.run().await
+        })
+    });
+    rx.recv().unwrap()
+});
+
+/// Status code of a GET request to the test server
+pub fn http_status(path: &str) -> u16 {
+    http_head(path)
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Status line and headers of a GET request to the test server
+pub fn http_head(path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", *SERVER_PORT)).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .unwrap();
+    // the body may be audio, only the status line is text
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response);
+    match response.split_once("\r\n\r\n") {
+        Some((head, _)) => head.to_string(),
+        None => response.to_string(),
+    }
+}
+
+/// `pattern` is either the exact message or a prefix followed by `*`
+fn matches(pattern: &str, message: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => message.starts_with(prefix),
+        None => message == pattern,
+    }
+}
+
+/// A player connected over a websocket, clock pings are skipped
+pub struct TestClient {
+    pub name: String,
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    /// Connects and sets the username
+    pub fn connect(name: &str) -> Self {
+        let (socket, _) =
+            tungstenite::connect(format!("ws://127.0.0.1:{}/ws", *SERVER_PORT)).unwrap();
+        let mut client = Self {
+            name: name.to_string(),
+            socket,
+        };
+        client.set_read_timeout(RECV_TIMEOUT);
+        client.expect(&["GTS v*", "song_route /songs", "audio_route /audio"]);
+        client.send(&format!("set_username {}", name));
+        client.expect(&["k"]);
+        client
+    }
+
+    pub fn send(&mut self, text: &str) {
+        self.socket.send(Message::Text(text.to_string())).unwrap();
+    }
+
+    pub fn recv(&mut self) -> String {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) if text.starts_with("clock_ping ") => continue,
+                Ok(Message::Text(text)) => return text,
+                Ok(_) => continue,
+                Err(err) => panic!("{} got no message: {}", self.name, err),
+            }
+        }
+    }
+
+    /// Asserts that nothing but clock pings arrives for `wait`
+    pub fn expect_silence(&mut self, wait: Duration) {
+        self.set_read_timeout(wait);
+        let received = loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) if text.starts_with("clock_ping ") => continue,
+                Ok(Message::Text(text)) => break Some(text),
+                Ok(_) => continue,
+                Err(_) => break None,
+            }
+        };
+        self.set_read_timeout(RECV_TIMEOUT);
+        if let Some(message) = received {
+            panic!("{} expected no message, got `{}`", self.name, message);
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) {
+        if let MaybeTlsStream::Plain(stream) = self.socket.get_ref() {
+            stream.set_read_timeout(Some(timeout)).unwrap();
+        }
+    }
+
+    /// Asserts the next messages in order, returns them to inspect the wildcard parts
+    pub fn expect(&mut self, expected: &[&str]) -> Vec<String> {
+        expected
+            .iter()
+            .map(|pattern| {
+                let message = self.recv();
+                assert!(
+                    matches(pattern, &message),
+                    "{} expected `{}`, got `{}`",
+                    self.name,
+                    pattern,
+                    message
+                );
+                message
+            })
+            .collect()
+    }
+
+    /// Asserts the next messages in any order, for messages sent from different threads
+    pub fn expect_unordered(&mut self, expected: &[&str]) -> Vec<String> {
+        let mut missing = expected.to_vec();
+        (0..expected.len())
+            .map(|_| {
+                let message = self.recv();
+                let Some(idx) = missing
+                    .iter()
+                    .position(|pattern| matches(pattern, &message))
+                else {
+                    panic!(
+                        "{} got unexpected `{}`, still expecting {:?}",
+                        self.name, message, missing
+                    );
+                };
+                missing.remove(idx);
+                message
+            })
+            .collect()
+    }
+
+    /// Creates a game and returns its ID
+    pub fn create_game(&mut self) -> u16 {
+        self.send("new");
+        let messages = self.expect(&[
+            "question_mode title_artist",
+            "snippet_mode full",
+            "game_created *",
+        ]);
+        messages[2]["game_created ".len()..].parse().unwrap()
+    }
+}
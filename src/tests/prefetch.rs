@@ -0,0 +1,55 @@
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use super::{RECV_TIMEOUT, SERVER_PORT};
+use crate::music_handler::{
+    download_queue::{self, DownloadRequester},
+    prefetch, song_cache, songs_dir,
+};
+
+#[test]
+fn players_join_background_downloads() {
+    Lazy::force(&SERVER_PORT);
+    download_queue::enqueue_background("mock:6");
+    let requester = DownloadRequester {
+        user_id: u32::MAX,
+        listener: None,
+    };
+    let song = download_queue::download("mock:6", requester).unwrap();
+    assert_eq!(song.title, "Golden Hour");
+    assert!(song_cache::contains("mock:6"));
+}
+
+#[test]
+fn added_songs_are_kept_warm() {
+    Lazy::force(&SERVER_PORT);
+    prefetch::record_adds(&["mock:7".to_string()]);
+    assert!(prefetch::most_added(usize::MAX).contains(&"mock:7".to_string()));
+
+    prefetch::warm_up();
+    let deadline = Instant::now() + RECV_TIMEOUT;
+    while !song_cache::contains("mock:7") {
+        assert!(Instant::now() < deadline, "mock:7 wasn't downloaded");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn songs_deleted_by_other_processes_are_downloaded_again() {
+    Lazy::force(&SERVER_PORT);
+    let requester = || DownloadRequester {
+        user_id: u32::MAX,
+        listener: None,
+    };
+    download_queue::download("mock:7", requester()).unwrap();
+    // like `cache prune` run next to the server, the index still has the song
+    fs::remove_file(songs_dir().join("mock:7")).unwrap();
+    assert!(song_cache::contains("mock:7"));
+
+    download_queue::download("mock:7", requester()).unwrap();
+    assert!(songs_dir().join("mock:7").exists());
+}
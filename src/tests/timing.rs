@@ -0,0 +1,98 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::game::{
+    clock::{Clock, VirtualClock},
+    set_game_clock,
+};
+
+use super::{TestClient, TEST_TIMINGS};
+
+/// how long to listen for messages that must not be sent yet
+const SILENCE: Duration = Duration::from_millis(50);
+const TICK: Duration = Duration::from_millis(1);
+
+/// A game of one player whose engine runs on a virtual clock
+fn solo_game(name: &str) -> (TestClient, Arc<VirtualClock>) {
+    let mut player = TestClient::connect(name);
+    let game_id = player.create_game();
+    let clock = Arc::new(VirtualClock::new());
+    set_game_clock(game_id, clock.clone());
+    (player, clock)
+}
+
+/// Readies up and steps through the countdown
+fn start_selection(player: &mut TestClient, clock: &VirtualClock) {
+    player.send("ready_up");
+    let messages = player.expect(&[
+        &format!("user_ready \"{}\"", player.name),
+        "game_start_at *",
+        "k",
+    ]);
+    let start_at: u128 = messages[1]["game_start_at ".len()..].parse().unwrap();
+    assert_eq!(
+        start_at,
+        clock.unix_ms() + TEST_TIMINGS.start_countdown.as_millis()
+    );
+
+    clock.wait_for_sleepers(1);
+    clock.advance(TEST_TIMINGS.start_countdown - TICK);
+    player.expect_silence(SILENCE);
+    clock.advance(TICK);
+    player.expect(&["game_start_select"]);
+}
+
+#[test]
+fn countdown_follows_the_clock() {
+    let (mut player, clock) = solo_game("dave");
+    start_selection(&mut player, &clock);
+}
+
+#[test]
+fn round_timeout_and_delays_follow_the_clock() {
+    let (mut player, clock) = solo_game("erin");
+    start_selection(&mut player, &clock);
+
+    player.send("add mock:5");
+    player.expect_unordered(&[
+        "selection_progress *",
+        "k",
+        "download_status mock:5 queued *",
+        "download_status mock:5 downloading",
+        "download_status mock:5 done",
+        r#"added_song ["Slow Satellite","Velvet Echo"]"#,
+        "selection_progress *",
+    ]);
+    player.send("start_guessing");
+    player.expect(&["game_start_guessing", "game_preload 0 *"]);
+    player.send("loaded 0");
+    let round_start = player.expect(&[
+        "game_play_audio *",
+        "game_question *",
+        "game_guess_options *",
+    ]);
+    let start_at: u128 = round_start[0].rsplit(' ').next().unwrap().parse().unwrap();
+    assert_eq!(
+        start_at,
+        clock.unix_ms() + TEST_TIMINGS.play_start_lead.as_millis()
+    );
+
+    // nobody guesses, the round ends once the guess timeout passed
+    clock.advance(TEST_TIMINGS.play_start_lead + TEST_TIMINGS.guess_timeout - TICK);
+    player.expect_silence(SILENCE);
+    clock.advance(TICK);
+    player.expect(&["correct *"]);
+
+    clock.wait_for_sleepers(1);
+    clock.advance(TEST_TIMINGS.reveal - TICK);
+    player.expect_silence(SILENCE);
+    clock.advance(TICK);
+    player.expect(&["leaderboard []"]);
+
+    clock.wait_for_sleepers(1);
+    clock.advance(TEST_TIMINGS.leaderboard);
+    clock.wait_for_sleepers(1);
+    clock.advance(TEST_TIMINGS.game_end - TICK);
+    player.expect_silence(SILENCE);
+    clock.advance(TICK);
+    player.expect(&["game_ended"]);
+}